@@ -0,0 +1,226 @@
+//! Word completion from the text of the open buffers: candidates are the
+//! words that extend what was typed, nearest and most used first.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use crossterm::style::Attribute;
+
+/// How many candidates the popup shows at once.
+pub const SHOWN:usize = 10;
+
+/// Words seen so far, with how often they occur and how close the nearest
+/// one is to the cursor, in rows.
+#[derive(Default)]
+pub struct Words{
+    found:HashMap<String, (usize, usize)>
+}
+
+impl Words{
+    /// Adds the words of `row` that start with `prefix` and are longer than
+    /// it. `distance` is how many rows the row is from the cursor; the word
+    /// spanning byte `except`, the one being typed, is left out.
+    pub fn scan(&mut self, row:&str, prefix:&str, distance:usize, except:Option<usize>, is_word:impl Fn(char) -> bool){
+        let mut start = None;
+        for (i, c) in row.char_indices().chain([(row.len(), ' ')]){
+            match (start, is_word(c)){
+                (None, true) => start = Some(i),
+                (Some(from), false) => {
+                    start = None;
+                    let word = &row[from..i];
+                    let typed = except.is_some_and(|at| (from..=i).contains(&at));
+                    // Numbers are words to the editor, but not worth offering.
+                    if typed || word.len() <= prefix.len() || !word.starts_with(prefix) || word.starts_with(|c:char| c.is_ascii_digit()){
+                        continue;
+                    }
+                    let entry = self.found.entry(word.to_string()).or_insert((0, distance));
+                    entry.0 += 1;
+                    entry.1 = entry.1.min(distance);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// The words found, best first. Distance counts in powers of two, so
+    /// that a word used often a few rows further away beats one used once
+    /// right next to the cursor.
+    pub fn ranked(self) -> Vec<String>{
+        let mut words:Vec<(String, (usize, usize))> = self.found.into_iter().collect();
+        words.sort_by_key(|(word, (count, distance))| {
+            (usize::BITS - distance.leading_zeros(), Reverse(*count), *distance, word.clone())
+        });
+        words.into_iter().map(|(word, _)| word).collect()
+    }
+}
+
+/// The open completion popup. The candidates are collected once, for the
+/// word as it was then, and narrowed down as more of it is typed. With
+/// none left the popup is hidden but kept, so that typing on does not
+/// collect them all over again.
+pub struct Popup{
+    /// Where the word being completed starts, as `(x, y)`.
+    pub start:(usize, usize),
+    /// What was typed when the candidates were collected.
+    prefix:String,
+    candidates:Vec<String>,
+    /// The candidates extending what is typed now.
+    pub words:Vec<String>,
+    pub selected:usize,
+    /// The first word shown, when there are more than fit.
+    pub top:usize
+}
+
+impl Popup{
+    pub fn new(start:(usize, usize), prefix:String, candidates:Vec<String>) -> Self{
+        let mut popup = Self{start, prefix, candidates, words:Vec::new(), selected:0, top:0};
+        popup.words = popup.candidates.clone();
+        popup
+    }
+
+    /// Keeps the words that extend `typed`, the word now being completed
+    /// from `start`. Returns `false` if the candidates were not collected
+    /// for it, as after deleting back past what was typed then.
+    pub fn narrow(&mut self, start:(usize, usize), typed:&str) -> bool{
+        if start != self.start || !typed.starts_with(self.prefix.as_str()){
+            return false;
+        }
+        self.words = self.candidates.iter().filter(|word| word.len() > typed.len() && word.starts_with(typed)).cloned().collect();
+        (self.selected, self.top) = (0, 0);
+        true
+    }
+
+    /// Whether nothing is left to offer, so the popup is not shown.
+    pub fn is_hidden(&self) -> bool{
+        self.words.is_empty()
+    }
+
+    /// Moves the selection down or up, wrapping around at the ends.
+    pub fn step(&mut self, forward:bool){
+        let len = self.words.len();
+        self.selected = if forward {(self.selected + 1) % len} else {(self.selected + len - 1) % len};
+        if self.selected < self.top{
+            self.top = self.selected;
+        }else if self.selected >= self.top + SHOWN{
+            self.top = self.selected + 1 - SHOWN;
+        }
+    }
+
+    pub fn selected_word(&self) -> &str{
+        &self.words[self.selected]
+    }
+
+    /// The words on screen, with whether each is the selected one.
+    pub fn shown(&self) -> impl Iterator<Item = (&str, bool)>{
+        self.words
+            .iter()
+            .enumerate()
+            .skip(self.top)
+            .take(SHOWN)
+            .map(move |(i, word)| (word.as_str(), i == self.selected))
+    }
+}
+
+/// Draws `text` in `style` over `line` from visible column `column` on.
+/// `line` may hold escape sequences; the ones under `text` are still
+/// applied after it, so the rest of the line keeps its attributes.
+pub fn overlay(line:&str, column:usize, text:&str, style:&str) -> String{
+    let width = text.chars().count();
+    let text = format!("{}{}{}", style, text, Attribute::Reset);
+    let mut result = String::new();
+    let mut escapes = String::new();
+    let mut visible = 0;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next(){
+        if c == '\x1b'{
+            // A CSI sequence runs up to its final byte in `@`..=`~`.
+            let mut sequence = String::from(c);
+            if chars.peek() == Some(&'['){
+                sequence.push(chars.next().unwrap());
+                for c in chars.by_ref(){
+                    sequence.push(c);
+                    if ('@'..='~').contains(&c){
+                        break;
+                    }
+                }
+            }
+            escapes.push_str(&sequence);
+            if visible < column || visible >= column + width{
+                result.push_str(&sequence);
+            }
+            continue;
+        }
+        if visible == column{
+            result.push_str(&text);
+        }else if visible == column + width{
+            result.push_str(&escapes);
+        }
+        if visible < column || visible >= column + width{
+            result.push(c);
+        }
+        visible += 1;
+    }
+    if visible <= column{
+        result.push_str(&" ".repeat(column - visible));
+        result.push_str(&text);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn is_word(c:char) -> bool{
+        c.is_alphanumeric() || c == '_'
+    }
+
+    fn words(list:&[&str]) -> Vec<String>{
+        list.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn ranks_near_then_frequent_words_first(){
+        let mut found = Words::default();
+        found.scan("alpine alpine", "al", 3, None, is_word);
+        found.scan("alpha al 42al", "al", 2, None, is_word);
+        found.scan("also", "al", 0, None, is_word);
+        // The word being typed, `alt` at byte 5, is left out.
+        found.scan("also alt alto", "al", 0, Some(5), is_word);
+        found.scan("almost", "al", 9, None, is_word);
+        assert_eq!(found.ranked(), words(&["also", "alto", "alpine", "alpha", "almost"]));
+    }
+
+    #[test]
+    fn steps_around_the_ends(){
+        let list:Vec<String> = (0..12).map(|i| format!("w{:02}", i)).collect();
+        let mut popup = Popup::new((0, 0), "w".into(), list);
+        popup.step(false);
+        assert_eq!((popup.selected_word(), popup.top), ("w11", 2));
+        assert_eq!(popup.shown().count(), SHOWN);
+        popup.step(true);
+        assert_eq!((popup.selected_word(), popup.top), ("w00", 0));
+        assert_eq!(popup.shown().next(), Some(("w00", true)));
+    }
+
+    #[test]
+    fn narrows_while_the_word_is_extended(){
+        let mut popup = Popup::new((4, 1), "al".into(), words(&["alpha", "alphabet", "alpine"]));
+        popup.step(true);
+        assert!(popup.narrow((4, 1), "alph"));
+        assert_eq!((popup.words.clone(), popup.selected), (words(&["alpha", "alphabet"]), 0));
+        assert!(popup.narrow((4, 1), "alphabet"));
+        assert!(popup.is_hidden());
+        assert!(!popup.narrow((4, 1), "a"));
+        assert!(!popup.narrow((5, 1), "alp"));
+    }
+
+    #[test]
+    fn overlays_plain_styled_and_short_lines(){
+        let reset = Attribute::Reset.to_string();
+        assert_eq!(overlay("hello world", 2, "XY", "<s>"), format!("he<s>XY{}o world", reset));
+        // Bold starting under the popup still applies after it.
+        assert_eq!(overlay("ab\x1b[1mcdef", 1, "XYZ", "<s>"), format!("a<s>XYZ{}\x1b[1mef", reset));
+        assert_eq!(overlay("\x1b[31mab", 4, "XY", "<s>"), format!("\x1b[31mab  <s>XY{}", reset));
+    }
+}
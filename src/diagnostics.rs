@@ -0,0 +1,223 @@
+//! Problems reported about a buffer, by a language server, a linter or the
+//! build. They are kept with the buffer, at byte positions in its rows.
+
+use std::fmt;
+
+use crate::quickfix::Entry;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity{
+    Error,
+    Warning,
+    Information,
+    Hint
+}
+
+impl Severity{
+    pub fn name(self) -> &'static str{
+        match self{
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Information => "info",
+            Severity::Hint => "hint",
+        }
+    }
+
+    /// The letter shown in the sign column.
+    pub fn sign(self) -> char{
+        match self{
+            Severity::Error => 'E',
+            Severity::Warning => 'W',
+            Severity::Information => 'I',
+            Severity::Hint => 'H',
+        }
+    }
+
+    /// Reads the `error`, `warning: `, `note[...]:` word tools start their
+    /// messages with.
+    fn from_word(word:&str) -> Option<Self>{
+        let word = word.split('[').next().unwrap_or(word);
+        match word.to_ascii_lowercase().as_str(){
+            "error" | "fatal error" => Some(Severity::Error),
+            "warning" => Some(Severity::Warning),
+            "note" | "info" => Some(Severity::Information),
+            "help" | "hint" => Some(Severity::Hint),
+            _ => None,
+        }
+    }
+}
+
+/// Where a diagnostic came from; each source replaces only its own.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Source{
+    Server,
+    Linter,
+    Build
+}
+
+#[derive(Clone)]
+pub struct Diagnostic{
+    /// `(x, y)` with `x` a byte offset into row `y`, like the cursor.
+    pub start:(usize, usize),
+    pub end:(usize, usize),
+    pub severity:Severity,
+    pub message:String,
+    pub source:Source
+}
+
+impl fmt::Display for Diagnostic{
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{}: {}", self.severity.name(), self.message.replace('\n', " "))
+    }
+}
+
+impl Diagnostic{
+    /// A diagnostic for a build or linter `entry` on `row`. Its 1-based
+    /// column counts characters; the range runs to the end of the word
+    /// there, or over the whole line when there is no column.
+    pub fn from_entry(entry:&Entry, row:&str, source:Source) -> Self{
+        let y = entry.line - 1;
+        let (x, end) = match entry.col{
+            Some(col) => {
+                let x = row.char_indices().nth(col.saturating_sub(1)).map_or(row.len(), |(i, _)| i);
+                let word = row[x..].find(|c:char| !(c.is_alphanumeric() || c == '_')).unwrap_or(row.len() - x);
+                // Punctuation still gets one character underlined.
+                let len = if word > 0 {word} else {row[x..].chars().next().map_or(0, char::len_utf8)};
+                (x, x + len)
+            },
+            None => (row.len() - row.trim_start().len(), row.trim_end().len()),
+        };
+
+        let (severity, message) = match entry.message.split_once(": "){
+            Some((word, rest)) => match Severity::from_word(word){
+                Some(severity) => (severity, rest.to_string()),
+                None => (Severity::Error, entry.message.clone()),
+            },
+            None => (Severity::Error, entry.message.clone()),
+        };
+        Diagnostic{start:(x, y), end:(end, y), severity, message, source}
+    }
+
+    /// The byte range it covers on row `y` of length `len`, if any.
+    pub fn columns_on(&self, y:usize, len:usize) -> Option<(usize, usize)>{
+        if y < self.start.1 || y > self.end.1{
+            return None;
+        }
+        let from = if y == self.start.1 {self.start.0} else {0};
+        let to = if y == self.end.1 {self.end.0} else {len};
+        Some((from.min(len), to.min(len)))
+    }
+}
+
+/// The diagnostics of one buffer, in order of position.
+#[derive(Default)]
+pub struct Diagnostics{
+    list:Vec<Diagnostic>,
+    /// How many rows the buffer had, to tell how far an edit moved them.
+    rows:usize
+}
+
+impl Diagnostics{
+    /// Replaces the diagnostics from `source` with `list`, for a buffer of `rows` rows.
+    pub fn set(&mut self, source:Source, list:Vec<Diagnostic>, rows:usize){
+        self.list.retain(|diagnostic| diagnostic.source != source);
+        self.list.extend(list);
+        self.list.sort_by_key(|diagnostic| (diagnostic.start.1, diagnostic.start.0));
+        self.rows = rows;
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.list.is_empty()
+    }
+
+    pub fn on_row(&self, y:usize) -> impl Iterator<Item = &Diagnostic>{
+        self.list.iter().filter(move |diagnostic| diagnostic.start.1 <= y && y <= diagnostic.end.1)
+    }
+
+    /// The most severe diagnostic starting on row `y`.
+    pub fn worst_on(&self, y:usize) -> Option<&Diagnostic>{
+        self.list.iter().filter(|diagnostic| diagnostic.start.1 == y).min_by_key(|diagnostic| diagnostic.severity)
+    }
+
+    /// The diagnostic after or before `(x, y)`, wrapping around at the ends.
+    pub fn next(&self, (x, y):(usize, usize), forward:bool) -> Option<&Diagnostic>{
+        let at = |diagnostic:&&Diagnostic| (diagnostic.start.1, diagnostic.start.0);
+        if forward{
+            self.list.iter().find(|diagnostic| at(diagnostic) > (y, x)).or_else(|| self.list.first())
+        }else{
+            self.list.iter().rev().find(|diagnostic| at(diagnostic) < (y, x)).or_else(|| self.list.last())
+        }
+    }
+
+    /// Follows an edit that rewrote the `count` rows from `at`, leaving the
+    /// buffer with `rows` rows. What was reported about the rewritten rows
+    /// no longer applies; rows below move along with the edit until the
+    /// source reports again.
+    pub fn edited(&mut self, at:usize, count:usize, rows:usize){
+        if self.list.is_empty(){
+            self.rows = rows;
+            return;
+        }
+        let shift = rows as isize - self.rows as isize;
+        let rewritten = at..at + count;
+        self.list.retain(|diagnostic| !rewritten.contains(&diagnostic.start.1) && !rewritten.contains(&diagnostic.end.1));
+        for diagnostic in self.list.iter_mut(){
+            for y in [&mut diagnostic.start.1, &mut diagnostic.end.1]{
+                if *y >= rewritten.end{
+                    *y = (*y as isize + shift).max(0) as usize;
+                }
+            }
+        }
+        self.rows = rows;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Diagnostics on rows `ys` of a buffer of `rows` rows.
+    fn on_rows(ys:&[usize], rows:usize) -> Diagnostics{
+        let list = ys.iter().map(|&y| Diagnostic{
+            start:(0, y),
+            end:(1, y),
+            severity:Severity::Error,
+            message:format!("row {}", y),
+            source:Source::Linter
+        }).collect();
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set(Source::Linter, list, rows);
+        diagnostics
+    }
+
+    fn rows_of(diagnostics:&Diagnostics) -> Vec<(usize, String)>{
+        diagnostics.list.iter().map(|diagnostic| (diagnostic.start.1, diagnostic.message.clone())).collect()
+    }
+
+    #[test]
+    fn drops_the_edited_row_and_moves_the_ones_below(){
+        let mut diagnostics = on_rows(&[1, 3, 5], 6);
+        diagnostics.edited(3, 1, 6);
+        assert_eq!(rows_of(&diagnostics), [(1, "row 1".into()), (5, "row 5".into())]);
+        // A row inserted above row 1.
+        diagnostics.edited(0, 0, 7);
+        assert_eq!(rows_of(&diagnostics), [(2, "row 1".into()), (6, "row 5".into())]);
+        // Rows 2 and 3 joined.
+        diagnostics.edited(2, 2, 6);
+        assert_eq!(rows_of(&diagnostics), [(5, "row 5".into())]);
+    }
+
+    #[test]
+    fn clears_everything_when_the_whole_buffer_is_replaced(){
+        let mut diagnostics = on_rows(&[0, 4, 9], 10);
+        diagnostics.edited(0, 10, 12);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn moves_rows_below_a_replaced_block_by_the_change_in_length(){
+        let mut diagnostics = on_rows(&[0, 2, 6], 8);
+        diagnostics.edited(1, 3, 6);
+        assert_eq!(rows_of(&diagnostics), [(0, "row 0".into()), (4, "row 6".into())]);
+    }
+}
@@ -0,0 +1,326 @@
+//! Text encodings the editor can load and save besides plain UTF-8.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding{
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Windows1252
+}
+
+/// Windows-1252 characters for bytes 0x80..=0x9F. The five bytes the code
+/// page leaves undefined decode to the matching C1 control so they survive
+/// a round trip.
+const WINDOWS_1252_HIGH:[char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+impl Encoding{
+    pub fn name(self) -> &'static str{
+        match self{
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "ISO-8859-1",
+            Encoding::Windows1252 => "Windows-1252",
+        }
+    }
+
+    /// Parses a name like `latin1` or `utf-16be`, also returning whether the
+    /// file should carry a BOM. UTF-16 gets one unless asked for in a
+    /// specific byte order by name, UTF-8 only as `utf-8-bom`.
+    pub fn from_name(name:&str) -> Option<(Self, bool)>{
+        let name = name.trim().to_ascii_lowercase().replace(['-', '_', ' '], "");
+        match name.as_str(){
+            "utf8" => Some((Encoding::Utf8, false)),
+            "utf8bom" => Some((Encoding::Utf8, true)),
+            "utf16" => Some((Encoding::Utf16Le, true)),
+            "utf16le" => Some((Encoding::Utf16Le, false)),
+            "utf16be" => Some((Encoding::Utf16Be, false)),
+            "latin1" | "iso88591" => Some((Encoding::Latin1, false)),
+            "windows1252" | "cp1252" => Some((Encoding::Windows1252, false)),
+            _ => None,
+        }
+    }
+
+    pub fn bom(self) -> &'static [u8]{
+        match self{
+            Encoding::Utf8 => &[0xEF, 0xBB, 0xBF],
+            Encoding::Utf16Le => &[0xFF, 0xFE],
+            Encoding::Utf16Be => &[0xFE, 0xFF],
+            Encoding::Latin1 | Encoding::Windows1252 => &[],
+        }
+    }
+
+    /// Guesses the encoding of a file from its first bytes: a byte order mark
+    /// wins, then UTF-16 given away by its zero bytes, then UTF-8, and
+    /// anything else that still looks like text is taken as a single-byte
+    /// code page. Returns `None` for binary data, otherwise the encoding and
+    /// whether the file starts with a BOM.
+    pub fn detect(head:&[u8]) -> Option<(Self, bool)>{
+        for encoding in [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be]{
+            if head.starts_with(encoding.bom()){
+                return Some((encoding, true));
+            }
+        }
+
+        let pairs = head.len() / 2;
+        if pairs > 0{
+            let even_zeros = head.iter().step_by(2).filter(|byte| **byte == 0).count();
+            let odd_zeros = head.iter().skip(1).step_by(2).filter(|byte| **byte == 0).count();
+            if odd_zeros * 5 > pairs * 2 && even_zeros * 20 < pairs{
+                return Some((Encoding::Utf16Le, false));
+            }
+            if even_zeros * 5 > pairs * 2 && odd_zeros * 20 < pairs{
+                return Some((Encoding::Utf16Be, false));
+            }
+        }
+
+        if head.contains(&0){
+            return None;
+        }
+        // A multi-byte character cut off at the end of `head` is still UTF-8.
+        match std::str::from_utf8(head){
+            Ok(_) => return Some((Encoding::Utf8, false)),
+            Err(err) if err.error_len().is_none() => return Some((Encoding::Utf8, false)),
+            Err(_) => {},
+        }
+
+        let controls = head
+            .iter()
+            .filter(|byte| **byte < 0x20 && !matches!(byte, b'\t' | b'\n' | b'\r' | 0x0C | 0x1B))
+            .count();
+        if controls * 20 > head.len(){
+            None
+        }else if head.iter().any(|byte| (0x80..0xA0).contains(byte)){
+            Some((Encoding::Windows1252, false))
+        }else{
+            Some((Encoding::Latin1, false))
+        }
+    }
+
+    /// The first character of `text` this encoding has no byte for.
+    pub fn unencodable(self, text:&str) -> Option<char>{
+        match self{
+            Encoding::Utf8 | Encoding::Utf16Le | Encoding::Utf16Be => None,
+            Encoding::Latin1 => text.chars().find(|c| *c as u32 > 0xFF),
+            Encoding::Windows1252 => text.chars().find(|c| windows_1252_byte(*c).is_none()),
+        }
+    }
+
+    /// Appends `text` to `out`. Characters rejected by `unencodable` become `?`.
+    pub fn encode(self, text:&str, out:&mut Vec<u8>){
+        match self{
+            Encoding::Utf8 => out.extend_from_slice(text.as_bytes()),
+            Encoding::Utf16Le => text.encode_utf16().for_each(|unit| out.extend(unit.to_le_bytes())),
+            Encoding::Utf16Be => text.encode_utf16().for_each(|unit| out.extend(unit.to_be_bytes())),
+            Encoding::Latin1 => out.extend(text.chars().map(|c| u8::try_from(c).unwrap_or(b'?'))),
+            Encoding::Windows1252 => out.extend(text.chars().map(|c| windows_1252_byte(c).unwrap_or(b'?'))),
+        }
+    }
+}
+
+fn windows_1252_byte(c:char) -> Option<u8>{
+    match c as u32{
+        0..=0x7F | 0xA0..=0xFF => Some(c as u8),
+        _ => WINDOWS_1252_HIGH.iter().position(|high| *high == c).map(|i| 0x80 + i as u8),
+    }
+}
+
+/// Incremental decoder for reading a file in chunks. Bytes that end in the
+/// middle of a character are held back until the next chunk arrives.
+pub struct Decoder{
+    encoding:Encoding,
+    pending:Vec<u8>,
+    /// Set once anything had to be replaced with U+FFFD.
+    pub lossy:bool
+}
+
+impl Decoder{
+    pub fn new(encoding:Encoding) -> Self{
+        Self{
+            encoding,
+            pending:Vec::new(),
+            lossy:false
+        }
+    }
+
+    pub fn decode(&mut self, bytes:&[u8], out:&mut String){
+        self.pending.extend_from_slice(bytes);
+        let used = match self.encoding{
+            Encoding::Utf8 => self.decode_utf8(out),
+            Encoding::Utf16Le | Encoding::Utf16Be => self.decode_utf16(out),
+            Encoding::Latin1 => {
+                out.extend(self.pending.iter().map(|&byte| byte as char));
+                self.pending.len()
+            },
+            Encoding::Windows1252 => {
+                out.extend(self.pending.iter().map(|&byte| match byte{
+                    0x80..=0x9F => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+                    _ => byte as char,
+                }));
+                self.pending.len()
+            },
+        };
+        self.pending.drain(..used);
+    }
+
+    /// Flushes whatever incomplete character is left at the end of the input.
+    pub fn finish(&mut self, out:&mut String){
+        if !self.pending.is_empty(){
+            out.push('\u{FFFD}');
+            self.lossy = true;
+            self.pending.clear();
+        }
+    }
+
+    fn decode_utf8(&mut self, out:&mut String) -> usize{
+        let mut start = 0;
+        loop{
+            match std::str::from_utf8(&self.pending[start..]){
+                Ok(text) => {
+                    out.push_str(text);
+                    return self.pending.len();
+                },
+                Err(err) => {
+                    let valid = start + err.valid_up_to();
+                    out.push_str(std::str::from_utf8(&self.pending[start..valid]).unwrap());
+                    match err.error_len(){
+                        Some(bad) => {
+                            out.push('\u{FFFD}');
+                            self.lossy = true;
+                            start = valid + bad;
+                        },
+                        None => return valid,
+                    }
+                },
+            }
+        }
+    }
+
+    fn decode_utf16(&mut self, out:&mut String) -> usize{
+        let little_endian = self.encoding == Encoding::Utf16Le;
+        let pending = &self.pending;
+        let unit = |i:usize| {
+            let bytes = [pending[2 * i], pending[2 * i + 1]];
+            if little_endian {u16::from_le_bytes(bytes)} else {u16::from_be_bytes(bytes)}
+        };
+
+        let mut units = pending.len() / 2;
+        // Keep a high surrogate back in case its pair is in the next chunk.
+        if units > 0 && (0xD800..0xDC00).contains(&unit(units - 1)){
+            units -= 1;
+        }
+        for decoded in char::decode_utf16((0..units).map(unit)){
+            match decoded{
+                Ok(c) => out.push(c),
+                Err(_) => {
+                    out.push('\u{FFFD}');
+                    self.lossy = true;
+                },
+            }
+        }
+        units * 2
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Decodes `chunks` one after the other, as a file is read.
+    fn decode(encoding:Encoding, chunks:&[&[u8]]) -> (String, bool){
+        let mut decoder = Decoder::new(encoding);
+        let mut out = String::new();
+        for chunk in chunks{
+            decoder.decode(chunk, &mut out);
+        }
+        decoder.finish(&mut out);
+        (out, decoder.lossy)
+    }
+
+    fn utf16(text:&str, little_endian:bool) -> Vec<u8>{
+        let mut out = Vec::new();
+        (if little_endian {Encoding::Utf16Le} else {Encoding::Utf16Be}).encode(text, &mut out);
+        out
+    }
+
+    #[test]
+    fn detects_byte_order_marks(){
+        assert_eq!(Encoding::detect(b"\xEF\xBB\xBFhi"), Some((Encoding::Utf8, true)));
+        assert_eq!(Encoding::detect(b"\xFF\xFEh\0i\0"), Some((Encoding::Utf16Le, true)));
+        assert_eq!(Encoding::detect(b"\xFE\xFF\0h\0i"), Some((Encoding::Utf16Be, true)));
+    }
+
+    #[test]
+    fn detects_utf16_without_a_bom(){
+        assert_eq!(Encoding::detect(&utf16("fn main() {}\n", true)), Some((Encoding::Utf16Le, false)));
+        assert_eq!(Encoding::detect(&utf16("fn main() {}\n", false)), Some((Encoding::Utf16Be, false)));
+    }
+
+    #[test]
+    fn falls_back_to_code_pages_and_binary(){
+        assert_eq!(Encoding::detect("caf\u{e9}\n".as_bytes()), Some((Encoding::Utf8, false)));
+        // Cut off in the middle of the `é`.
+        assert_eq!(Encoding::detect(b"caf\xC3"), Some((Encoding::Utf8, false)));
+        assert_eq!(Encoding::detect(b"caf\xE9\n"), Some((Encoding::Latin1, false)));
+        assert_eq!(Encoding::detect(b"\x93quoted\x94\n"), Some((Encoding::Windows1252, false)));
+        assert_eq!(Encoding::detect(&[0, 1, 2, 3, 0, 5, 6, 0]), None);
+    }
+
+    #[test]
+    fn parses_encoding_names(){
+        assert_eq!(Encoding::from_name(" UTF-8-BOM "), Some((Encoding::Utf8, true)));
+        assert_eq!(Encoding::from_name("utf16"), Some((Encoding::Utf16Le, true)));
+        assert_eq!(Encoding::from_name("utf_16be"), Some((Encoding::Utf16Be, false)));
+        assert_eq!(Encoding::from_name("cp1252"), Some((Encoding::Windows1252, false)));
+        assert_eq!(Encoding::from_name("ebcdic"), None);
+    }
+
+    #[test]
+    fn decodes_characters_split_across_chunks(){
+        assert_eq!(decode(Encoding::Utf8, &[b"caf\xC3", b"\xA9!"]), ("caf\u{e9}!".into(), false));
+        let smiley = utf16("a\u{1F600}", true);
+        assert_eq!(smiley.len(), 6);
+        for split in 1..smiley.len(){
+            let (head, tail) = smiley.split_at(split);
+            assert_eq!(decode(Encoding::Utf16Le, &[head, tail]), ("a\u{1F600}".into(), false));
+        }
+        let smiley = utf16("\u{1F600}", false);
+        assert_eq!(decode(Encoding::Utf16Be, &[&smiley[..2], &smiley[2..]]), ("\u{1F600}".into(), false));
+    }
+
+    #[test]
+    fn replaces_what_does_not_decode(){
+        assert_eq!(decode(Encoding::Utf8, &[b"a\xFFb"]), ("a\u{FFFD}b".into(), true));
+        assert_eq!(decode(Encoding::Utf8, &[b"caf\xC3"]), ("caf\u{FFFD}".into(), true));
+        // A low surrogate on its own.
+        assert_eq!(decode(Encoding::Utf16Le, &[b"\x00\xDCa\0"]), ("\u{FFFD}a".into(), true));
+    }
+
+    #[test]
+    fn round_trips_windows_1252(){
+        let bytes = b"\x80 \x93x\x94 \x81 \xE9";
+        let (text, lossy) = decode(Encoding::Windows1252, &[bytes]);
+        assert_eq!(text, "\u{20AC} \u{201C}x\u{201D} \u{81} \u{e9}");
+        assert!(!lossy);
+        let mut out = Vec::new();
+        Encoding::Windows1252.encode(&text, &mut out);
+        assert_eq!(out, bytes);
+    }
+
+    #[test]
+    fn finds_and_replaces_unencodable_characters(){
+        assert_eq!(Encoding::Latin1.unencodable("caf\u{e9} \u{20AC}5"), Some('\u{20AC}'));
+        assert_eq!(Encoding::Windows1252.unencodable("caf\u{e9} \u{20AC}5"), None);
+        assert_eq!(Encoding::Windows1252.unencodable("\u{3042}"), Some('\u{3042}'));
+        assert_eq!(Encoding::Utf16Le.unencodable("\u{3042}"), None);
+        let mut out = Vec::new();
+        Encoding::Latin1.encode("\u{e9}\u{20AC}", &mut out);
+        assert_eq!(out, b"\xE9?");
+    }
+}
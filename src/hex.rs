@@ -0,0 +1,186 @@
+//! Byte buffer behind the hex editor. The screen shows it as a dump of
+//! `ROW_BYTES` bytes per row: offset, hex columns and an ASCII sidebar.
+
+use std::{cmp, fs};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use crate::save;
+
+pub const ROW_BYTES: usize = 16;
+/// Width of the offset column, including the gap after it.
+const OFFSET_WIDTH: usize = 10;
+
+pub struct HexBuffer{
+    pub bytes:Vec<u8>,
+    pub filename:PathBuf,
+    /// Byte under the cursor; `bytes.len()` is the append position.
+    pub cursor:usize,
+    /// Whether the next hex digit typed goes into the low nibble.
+    pub low_nibble:bool,
+    /// Set when only part of the file was loaded, so it must not be saved.
+    pub read_only:bool
+}
+
+impl HexBuffer{
+    /// Reads at most `limit` bytes of `file`, read-only if it is any longer.
+    pub fn load(file:PathBuf, limit:u64) -> io::Result<Self>{
+        let mut bytes = Vec::new();
+        fs::File::open(&file)?.take(limit + 1).read_to_end(&mut bytes)?;
+        let read_only = bytes.len() as u64 > limit;
+        bytes.truncate(limit as usize);
+        Ok(Self{bytes, filename:file, cursor:0, low_nibble:false, read_only})
+    }
+
+    pub fn num_rows(&self) -> usize{
+        self.bytes.len().div_ceil(ROW_BYTES)
+    }
+
+    /// Row `at` of the dump, hex columns padded out on the last row.
+    pub fn dump_row(&self, at:usize) -> String{
+        let start = at * ROW_BYTES;
+        let row = &self.bytes[start..cmp::min(start + ROW_BYTES, self.bytes.len())];
+        let mut line = format!("{:08x}  ", start);
+        for col in 0..ROW_BYTES{
+            match row.get(col){
+                Some(byte) => line.push_str(&format!("{:02x} ", byte)),
+                None => line.push_str("   "),
+            }
+        }
+        line.push_str(" |");
+        line.extend(row.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' {byte as char} else {'.'}));
+        line.push('|');
+        line
+    }
+
+    /// Where the cursor sits in the dump, as `(cursor_x, row)`.
+    pub fn position(&self) -> (usize, usize){
+        let col = self.cursor % ROW_BYTES;
+        (OFFSET_WIDTH + col * 3 + self.low_nibble as usize, self.cursor / ROW_BYTES)
+    }
+
+    /// The byte shown at `(x, row)` of the dump, for mouse clicks.
+    pub fn byte_at(&self, x:usize, row:usize) -> usize{
+        let col = (x.saturating_sub(OFFSET_WIDTH) / 3).min(ROW_BYTES - 1);
+        (row * ROW_BYTES + col).min(self.bytes.len())
+    }
+
+    pub fn move_to(&mut self, at:usize){
+        self.cursor = at.min(self.bytes.len());
+        self.low_nibble = false;
+    }
+
+    /// Overwrites the nibble under the cursor, appending a byte at the end,
+    /// and moves on to the next nibble.
+    pub fn set_nibble(&mut self, value:u8){
+        if self.cursor == self.bytes.len(){
+            self.bytes.push(0);
+        }
+        let byte = &mut self.bytes[self.cursor];
+        *byte = if self.low_nibble {(*byte & 0xf0) | value} else {(*byte & 0x0f) | (value << 4)};
+        if self.low_nibble{
+            self.cursor += 1;
+        }
+        self.low_nibble = !self.low_nibble;
+    }
+
+    pub fn insert_byte(&mut self){
+        self.bytes.insert(self.cursor, 0);
+        self.low_nibble = false;
+    }
+
+    /// Removes the byte under the cursor; `false` at the end of the buffer.
+    pub fn delete_byte(&mut self) -> bool{
+        if self.cursor == self.bytes.len(){
+            return false;
+        }
+        self.bytes.remove(self.cursor);
+        self.low_nibble = false;
+        true
+    }
+
+    /// Next occurrence of `pattern` after the cursor, wrapping around.
+    pub fn find(&self, pattern:&[u8]) -> Option<usize>{
+        let matches_at = |at:&usize| self.bytes[*at..].starts_with(pattern);
+        let last = self.bytes.len().checked_sub(pattern.len())?;
+        (self.cursor + 1..=last).find(matches_at).or_else(|| (0..=cmp::min(self.cursor, last)).find(matches_at))
+    }
+
+    pub fn save(&self) -> io::Result<usize>{
+        save::write_file(&self.filename, |mut out| out.write_all(&self.bytes))?;
+        Ok(self.bytes.len())
+    }
+}
+
+/// Parses a byte pattern such as `de ad be ef` or `DEADBEEF`. Text in
+/// double quotes stands for its UTF-8 bytes, as in `"GET /" 0d 0a`.
+pub fn parse_pattern(input:&str) -> Option<Vec<u8>>{
+    if input.matches('"').count() % 2 == 1{
+        return None;
+    }
+    let mut bytes = Vec::new();
+    let mut high = None;
+    // Every other part is quoted.
+    for (i, part) in input.split('"').enumerate(){
+        if i % 2 == 1{
+            if high.is_some(){
+                return None;
+            }
+            bytes.extend_from_slice(part.as_bytes());
+            continue;
+        }
+        for c in part.chars().filter(|c| !c.is_whitespace()){
+            let digit = c.to_digit(16)? as u8;
+            match high.take(){
+                Some(high) => bytes.push((high << 4) | digit),
+                None => high = Some(digit),
+            }
+        }
+    }
+    if bytes.is_empty() || high.is_some(){
+        return None;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn buffer(bytes:&[u8], cursor:usize) -> HexBuffer{
+        HexBuffer{bytes:bytes.to_vec(), filename:PathBuf::new(), cursor, low_nibble:false, read_only:false}
+    }
+
+    #[test]
+    fn parses_hex_and_quoted_text(){
+        assert_eq!(parse_pattern("de ad BE EF"), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(parse_pattern("DEADbeef"), Some(vec![0xde, 0xad, 0xbe, 0xef]));
+        assert_eq!(parse_pattern("\"GET /\" 0d 0a"), Some(b"GET /\r\n".to_vec()));
+        assert_eq!(parse_pattern("00\"\u{e9}\"00"), Some(vec![0, 0xc3, 0xa9, 0]));
+        assert_eq!(parse_pattern("\"a b\"\"\""), Some(b"a b".to_vec()));
+    }
+
+    #[test]
+    fn rejects_odd_and_invalid_patterns(){
+        assert_eq!(parse_pattern("abc"), None);
+        assert_eq!(parse_pattern("a\"x\"b"), None);
+        assert_eq!(parse_pattern("zz"), None);
+        assert_eq!(parse_pattern("0x41"), None);
+        assert_eq!(parse_pattern("\"open"), None);
+        assert_eq!(parse_pattern("  "), None);
+        assert_eq!(parse_pattern("\"\""), None);
+    }
+
+    #[test]
+    fn finds_after_the_cursor_and_wraps_around(){
+        let bytes = b"ab..ab..ab";
+        assert_eq!(buffer(bytes, 0).find(b"ab"), Some(4));
+        assert_eq!(buffer(bytes, 4).find(b"ab"), Some(8));
+        assert_eq!(buffer(bytes, 8).find(b"ab"), Some(0));
+        assert_eq!(buffer(bytes, 10).find(b"ab"), Some(0));
+        // The match under the cursor counts only once everything else was tried.
+        assert_eq!(buffer(b"xab", 1).find(b"ab"), Some(1));
+        assert_eq!(buffer(bytes, 0).find(b"abc"), None);
+        assert_eq!(buffer(b"a", 0).find(b"ab"), None);
+    }
+}
@@ -0,0 +1,716 @@
+//! Client side of the Language Server Protocol: JSON-RPC messages to and
+//! from a server process over its stdin and stdout, one open document at a
+//! time. Positions are in UTF-16 code units, as the protocol defaults to.
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::diagnostics::Severity;
+use crate::shell;
+
+/// How long a request may keep the editor waiting for its answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Servers index the project before answering `initialize`.
+const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(20);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+/// Larger messages are taken for a broken stream rather than read.
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Position{
+    pub line:usize,
+    pub character:usize
+}
+
+#[derive(Clone)]
+pub struct Diagnostic{
+    pub start:Position,
+    pub end:Position,
+    pub severity:Severity,
+    pub message:String
+}
+
+pub struct Location{
+    pub path:PathBuf,
+    pub start:Position
+}
+
+pub struct TextEdit{
+    pub start:Position,
+    pub end:Position,
+    pub new_text:String
+}
+
+/// The document the server was last told about.
+struct Document{
+    uri:String,
+    version:i64,
+    /// `EditorRows::revision` of the text last sent.
+    revision:u64
+}
+
+pub struct Server{
+    /// `None` when the server is not a process of its own, as in tests.
+    child:Option<Child>,
+    stdin:Box<dyn Write>,
+    /// Messages from the server, ending with an error if its output
+    /// stops making sense.
+    incoming:Receiver<io::Result<Value>>,
+    next_id:u64,
+    /// The id of the `initialize` request and when to give up on it, until
+    /// the server has answered it. Nothing else may be sent before that.
+    initializing:Option<(u64, Instant)>,
+    document:Option<Document>,
+    /// For the open document, as last published by the server.
+    pub diagnostics:Vec<Diagnostic>
+}
+
+impl Server{
+    /// Starts `command` through the shell and asks it to initialize for the
+    /// project in `root`. The answer is picked up by `poll`; until then the
+    /// server is not `ready`.
+    pub fn start(command:&str, root:&Path) -> io::Result<Self>{
+        let mut child = shell::shell_command(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // Anything it logs would scribble over the screen.
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        Self::connect(Some(child), stdout, Box::new(stdin), root)
+    }
+
+    /// Starts talking to a server that reads what is written to `stdin`
+    /// and answers on `stdout`.
+    fn connect(child:Option<Child>, stdout:impl Read + Send + 'static, stdin:Box<dyn Write>, root:&Path) -> io::Result<Self>{
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || read_messages(stdout, sender));
+
+        let mut server = Self{child, stdin, incoming, next_id:0, initializing:None, document:None, diagnostics:Vec::new()};
+        let params = json!({
+            "processId": std::process::id(),
+            "rootUri": path_to_uri(root),
+            "capabilities": {
+                "textDocument": {
+                    "synchronization": {"didSave": true},
+                    "publishDiagnostics": {},
+                    "hover": {"contentFormat": ["plaintext", "markdown"]},
+                    "completion": {"completionItem": {"snippetSupport": false}},
+                    "definition": {},
+                    "references": {},
+                    "rename": {}
+                }
+            }
+        });
+        let id = server.send_request("initialize", params)?;
+        server.initializing = Some((id, Instant::now() + INITIALIZE_TIMEOUT));
+        Ok(server)
+    }
+
+    pub fn ready(&self) -> bool{
+        self.initializing.is_none()
+    }
+
+    /// Makes sure the server has `path` open with the text at `revision`,
+    /// calling `text` only when it has to be sent.
+    pub fn sync(&mut self, path:&Path, language_id:&str, revision:u64, text:impl FnOnce() -> String) -> io::Result<()>{
+        if !self.ready(){
+            return Ok(());
+        }
+        let uri = path_to_uri(path);
+        match &mut self.document{
+            Some(document) if document.uri == uri => {
+                if document.revision == revision{
+                    return Ok(());
+                }
+                document.version += 1;
+                document.revision = revision;
+                let params = json!({
+                    "textDocument": {"uri": uri, "version": document.version},
+                    "contentChanges": [{"text": text()}]
+                });
+                self.notify("textDocument/didChange", params)
+            },
+            _ => {
+                self.close()?;
+                let params = json!({
+                    "textDocument": {"uri": uri, "languageId": language_id, "version": 0, "text": text()}
+                });
+                self.document = Some(Document{uri, version:0, revision});
+                self.notify("textDocument/didOpen", params)
+            },
+        }
+    }
+
+    pub fn saved(&mut self) -> io::Result<()>{
+        match &self.document{
+            Some(document) => {
+                let params = json!({"textDocument": {"uri": document.uri}});
+                self.notify("textDocument/didSave", params)
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()>{
+        self.diagnostics.clear();
+        match self.document.take(){
+            Some(document) => self.notify("textDocument/didClose", json!({"textDocument": {"uri": document.uri}})),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends a request about `at` in the open document and waits for the result.
+    pub fn request_at(&mut self, method:&str, at:Position, mut params:Value) -> io::Result<Value>{
+        let uri = match &self.document{
+            Some(document) => document.uri.clone(),
+            None if !self.ready() => return Err(io::Error::other("still starting")),
+            None => return Err(io::Error::other("no document open")),
+        };
+        params["textDocument"] = json!({"uri": uri});
+        params["position"] = json!({"line": at.line, "character": at.character});
+        self.request_within(method, params, REQUEST_TIMEOUT)
+    }
+
+    /// Handles whatever the server sent in the meantime. Returns whether the
+    /// diagnostics changed, or an error once the server has gone away.
+    pub fn poll(&mut self) -> io::Result<bool>{
+        if let Some((_, deadline)) = self.initializing{
+            if Instant::now() > deadline{
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no answer to initialize"));
+            }
+        }
+        let mut changed = false;
+        loop{
+            match self.incoming.try_recv(){
+                Ok(message) => changed |= self.handle(message?)?,
+                Err(TryRecvError::Empty) => return Ok(changed),
+                Err(TryRecvError::Disconnected) => return Err(io::Error::other("language server exited")),
+            }
+        }
+    }
+
+    fn request_within(&mut self, method:&str, params:Value, timeout:Duration) -> io::Result<Value>{
+        let id = self.send_request(method, params)?;
+        let deadline = Instant::now() + timeout;
+        loop{
+            let message = match self.incoming.recv_timeout(deadline.saturating_duration_since(Instant::now())){
+                Ok(message) => message?,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer to {}", method)));
+                },
+                Err(RecvTimeoutError::Disconnected) => return Err(io::Error::other("language server exited")),
+            };
+            if is_answer(&message, id){
+                return result(message);
+            }
+            self.handle(message)?;
+        }
+    }
+
+    /// Sends a request without waiting for the answer; returns its id.
+    fn send_request(&mut self, method:&str, params:Value) -> io::Result<u64>{
+        self.next_id += 1;
+        let mut message = json!({"jsonrpc": "2.0", "id": self.next_id, "method": method});
+        if !params.is_null(){
+            message["params"] = params;
+        }
+        self.send(message)?;
+        Ok(self.next_id)
+    }
+
+    /// Deals with a notification or request from the server; answers to
+    /// requests given up on are dropped. Returns whether diagnostics changed.
+    fn handle(&mut self, message:Value) -> io::Result<bool>{
+        if let Some((id, _)) = self.initializing{
+            if is_answer(&message, id){
+                result(message)?;
+                self.initializing = None;
+                self.notify("initialized", json!({}))?;
+                return Ok(false);
+            }
+        }
+        let method = match message["method"].as_str(){
+            Some(method) => method,
+            None => return Ok(false),
+        };
+        if let Some(id) = message.get("id"){
+            // Nothing the server might ask for is supported, but it must get an answer.
+            self.send(json!({"jsonrpc": "2.0", "id": id, "result": null}))?;
+            return Ok(false);
+        }
+        if method != "textDocument/publishDiagnostics"{
+            return Ok(false);
+        }
+        let params = &message["params"];
+        match &self.document{
+            Some(document) if params["uri"].as_str() == Some(document.uri.as_str()) => {},
+            _ => return Ok(false),
+        }
+        self.diagnostics = params["diagnostics"]
+            .as_array()
+            .map(|diagnostics| diagnostics.iter().filter_map(parse_diagnostic).collect())
+            .unwrap_or_default();
+        self.diagnostics.sort_by_key(|diagnostic| (diagnostic.start.line, diagnostic.start.character));
+        Ok(true)
+    }
+
+    fn notify(&mut self, method:&str, params:Value) -> io::Result<()>{
+        let mut message = json!({"jsonrpc": "2.0", "method": method});
+        if !params.is_null(){
+            message["params"] = params;
+        }
+        self.send(message)
+    }
+
+    fn send(&mut self, message:Value) -> io::Result<()>{
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.stdin.flush()
+    }
+}
+
+impl Drop for Server{
+    fn drop(&mut self){
+        if self.ready() && self.request_within("shutdown", Value::Null, SHUTDOWN_TIMEOUT).is_ok(){
+            let _ = self.notify("exit", Value::Null);
+            let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+            while Instant::now() < deadline{
+                match self.child.as_mut().map(Child::try_wait){
+                    None | Some(Ok(Some(_))) => return,
+                    _ => thread::sleep(Duration::from_millis(10)),
+                }
+            }
+        }
+        if let Some(child) = &mut self.child{
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn is_answer(message:&Value, id:u64) -> bool{
+    message.get("method").is_none() && message["id"] == json!(id)
+}
+
+/// The result an answer carries, or the error it reports.
+fn result(mut message:Value) -> io::Result<Value>{
+    if let Some(error) = message.get("error"){
+        return Err(io::Error::other(error["message"].as_str().unwrap_or("request failed").to_string()));
+    }
+    Ok(message["result"].take())
+}
+
+/// Splits the server's output into messages until it closes it, or until
+/// a message has no sensible length, which is passed on as an error.
+fn read_messages(stdout:impl Read, sender:mpsc::Sender<io::Result<Value>>){
+    let mut reader = BufReader::new(stdout);
+    loop{
+        let body = match read_message(&mut reader){
+            Ok(Some(body)) => body,
+            Ok(None) => return,
+            Err(err) => {
+                let _ = sender.send(Err(err));
+                return;
+            },
+        };
+        if let Ok(message) = serde_json::from_slice(&body){
+            if sender.send(Ok(message)).is_err(){
+                return;
+            }
+        }
+    }
+}
+
+/// The body of the next message, or `None` once the stream ends.
+fn read_message(reader:&mut impl BufRead) -> io::Result<Option<Vec<u8>>>{
+    let mut length = None;
+    loop{
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0{
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty(){
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':'){
+            if name.eq_ignore_ascii_case("Content-Length"){
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = match length{
+        Some(length) if length <= MAX_MESSAGE => length,
+        Some(length) => return Err(protocol_error(format!("a message of {} bytes", length))),
+        None => return Err(protocol_error("a message without a length".into())),
+    };
+    let mut body = Vec::with_capacity(length);
+    reader.take(length as u64).read_to_end(&mut body)?;
+    if body.len() < length{
+        return Ok(None);
+    }
+    Ok(Some(body))
+}
+
+fn protocol_error(what:String) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, format!("protocol error: {}", what))
+}
+
+fn parse_position(value:&Value) -> Option<Position>{
+    Some(Position{
+        line:value["line"].as_u64()? as usize,
+        character:value["character"].as_u64()? as usize
+    })
+}
+
+fn parse_diagnostic(value:&Value) -> Option<Diagnostic>{
+    let severity = match value["severity"].as_u64(){
+        Some(2) => Severity::Warning,
+        Some(3) => Severity::Information,
+        Some(4) => Severity::Hint,
+        _ => Severity::Error,
+    };
+    Some(Diagnostic{
+        start:parse_position(&value["range"]["start"])?,
+        end:parse_position(&value["range"]["end"])?,
+        severity,
+        message:value["message"].as_str()?.to_string()
+    })
+}
+
+/// The text of a hover result, flattened onto one line for the message bar,
+/// without Markdown code fences.
+pub fn hover_text(result:&Value) -> Option<String>{
+    let contents = &result["contents"];
+    let text = contents
+        .as_array()
+        .map_or(std::slice::from_ref(contents), Vec::as_slice)
+        .iter()
+        .filter_map(|item| item.as_str().or_else(|| item["value"].as_str()))
+        .flat_map(str::lines)
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("```") && *line != "---")
+        .collect::<Vec<_>>()
+        .join(" ");
+    if text.is_empty() {None} else {Some(text)}
+}
+
+/// Locations in a definition or references result: a `Location`, a list
+/// of them, or a list of `LocationLink`s.
+pub fn locations(result:&Value) -> Vec<Location>{
+    let items = result.as_array().map_or(std::slice::from_ref(result), Vec::as_slice);
+    items
+        .iter()
+        .filter_map(|item| {
+            let (uri, range) = match item.get("targetUri"){
+                Some(uri) => (uri, &item["targetSelectionRange"]),
+                None => (&item["uri"], &item["range"]),
+            };
+            Some(Location{path:uri_to_path(uri.as_str()?)?, start:parse_position(&range["start"])?})
+        })
+        .collect()
+}
+
+/// The edits a rename asks for, by file, from either form of `WorkspaceEdit`.
+pub fn workspace_edits(result:&Value) -> Vec<(PathBuf, Vec<TextEdit>)>{
+    let mut files = Vec::new();
+    let mut add = |uri:&Value, edits:&Value| {
+        if let (Some(path), Some(edits)) = (uri.as_str().and_then(uri_to_path), edits.as_array()){
+            files.push((path, edits.iter().filter_map(parse_text_edit).collect()));
+        }
+    };
+    if let Some(changes) = result["documentChanges"].as_array(){
+        for change in changes{
+            add(&change["textDocument"]["uri"], &change["edits"]);
+        }
+    }else if let Some(changes) = result["changes"].as_object(){
+        for (uri, edits) in changes{
+            add(&Value::String(uri.clone()), edits);
+        }
+    }
+    files
+}
+
+fn parse_text_edit(value:&Value) -> Option<TextEdit>{
+    Some(TextEdit{
+        start:parse_position(&value["range"]["start"])?,
+        end:parse_position(&value["range"]["end"])?,
+        new_text:value["newText"].as_str()?.to_string()
+    })
+}
+
+/// What each item of a completion result would insert.
+pub fn completions(result:&Value) -> Vec<String>{
+    let items = result.as_array().or_else(|| result["items"].as_array());
+    let mut words:Vec<String> = items
+        .into_iter()
+        .flatten()
+        .filter_map(|item| {
+            item["textEdit"]["newText"].as_str()
+                .or_else(|| item["insertText"].as_str())
+                .or_else(|| item["label"].as_str())
+                .map(String::from)
+        })
+        .collect();
+    words.dedup();
+    words
+}
+
+/// Applies `edits` to `rows`, which must all be against the same text.
+pub fn apply_edits(rows:&mut Vec<String>, mut edits:Vec<TextEdit>){
+    // From the end backwards, so earlier positions stay valid.
+    edits.sort_by_key(|edit| (edit.start.line, edit.start.character));
+    for edit in edits.into_iter().rev(){
+        if rows.is_empty(){
+            rows.push(String::new());
+        }
+        let last = rows.len() - 1;
+        let (start_line, end_line) = (edit.start.line.min(last), edit.end.line.min(last));
+        let start = byte_col(&rows[start_line], edit.start.character);
+        let end = if edit.end.line > last {rows[last].len()} else {byte_col(&rows[end_line], edit.end.character)};
+        let text = format!("{}{}{}", &rows[start_line][..start], edit.new_text, &rows[end_line][end..]);
+        let lines:Vec<String> = text.split('\n').map(|line| line.trim_end_matches('\r').to_string()).collect();
+        rows.splice(start_line..=end_line, lines);
+    }
+}
+
+/// UTF-16 column of byte offset `byte` in `text`.
+pub fn utf16_col(text:&str, byte:usize) -> usize{
+    text[..byte.min(text.len())].encode_utf16().count()
+}
+
+/// Byte offset of UTF-16 column `col` in `text`, clamped to its end.
+pub fn byte_col(text:&str, col:usize) -> usize{
+    let mut units = 0;
+    for (i, c) in text.char_indices(){
+        if units >= col{
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+pub fn path_to_uri(path:&Path) -> String{
+    let path = path.canonicalize().unwrap_or_else(|_| env::current_dir().unwrap_or_default().join(path));
+    let mut uri = String::from("file://");
+    let text = path.to_string_lossy().replace('\\', "/");
+    if !text.starts_with('/'){
+        uri.push('/');
+    }
+    for byte in text.bytes(){
+        if byte.is_ascii_alphanumeric() || b"/-_.~:".contains(&byte){
+            uri.push(byte as char);
+        }else{
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+fn uri_to_path(uri:&str) -> Option<PathBuf>{
+    let encoded = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first(){
+        match (byte, tail.get(..2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())){
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            },
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            },
+        }
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    // `file:///C:/x` on Windows.
+    let path = match path.as_bytes(){
+        [b'/', drive, b':', b'/', ..] if drive.is_ascii_alphabetic() => &path[1..],
+        _ => &path[..],
+    };
+    Some(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// A frame of the wire format holding `message`.
+    fn frame(message:Value) -> String{
+        let body = message.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn answer(request:&Value, result:Value) -> String{
+        frame(json!({"jsonrpc": "2.0", "id": request["id"], "result": result}))
+    }
+
+    /// What a well-behaved server sends back for `message`.
+    fn standard(message:&Value) -> Vec<String>{
+        let uri = &message["params"]["textDocument"]["uri"];
+        match message["method"].as_str().unwrap_or_default(){
+            "initialize" => vec![answer(message, json!({"capabilities": {}}))],
+            "textDocument/didOpen" => vec![frame(json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/publishDiagnostics",
+                "params": {"uri": uri, "diagnostics": [
+                    {"range": {"start": {"line": 1, "character": 4}, "end": {"line": 1, "character": 7}}, "severity": 2, "message": "unused"},
+                    {"range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 2}}, "message": "broken"}
+                ]}
+            }))],
+            "textDocument/hover" => vec![answer(message, json!({"contents": {"kind": "markdown", "value": "```rust\nfn foo()\n```"}}))],
+            "textDocument/definition" => vec![answer(message, json!([
+                {"uri": "file:///src/a%20b.rs", "range": {"start": {"line": 3, "character": 5}, "end": {"line": 3, "character": 8}}}
+            ]))],
+            "textDocument/rename" => vec![answer(message, json!({"changes": {uri.as_str().unwrap(): [
+                {"range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 6}}, "newText": "bar"}
+            ]}}))],
+            _ if message.get("id").is_some() => vec![answer(message, Value::Null)],
+            _ => Vec::new(),
+        }
+    }
+
+    /// A server at the other end of two pipes, sending what `reply` returns
+    /// for each message, or going away when it returns `None`. The messages
+    /// it got come out of the receiver.
+    fn fake(reply:impl Fn(&Value) -> Option<Vec<String>> + Send + 'static) -> (Server, Receiver<Value>){
+        let (from_editor, stdin) = io::pipe().unwrap();
+        let (stdout, mut to_editor) = io::pipe().unwrap();
+        let (sender, received) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(from_editor);
+            while let Ok(Some(body)) = read_message(&mut reader){
+                let message:Value = serde_json::from_slice(&body).unwrap();
+                let replies = match reply(&message){
+                    Some(replies) => replies,
+                    None => return,
+                };
+                let _ = sender.send(message);
+                for reply in replies{
+                    if to_editor.write_all(reply.as_bytes()).is_err(){
+                        return;
+                    }
+                }
+            }
+        });
+        (Server::connect(None, stdout, Box::new(stdin), Path::new("/")).unwrap(), received)
+    }
+
+    /// Polls until `done` holds, failing after a few seconds.
+    fn poll_until(server:&mut Server, mut done:impl FnMut(&mut Server, io::Result<bool>) -> bool){
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop{
+            let polled = server.poll();
+            if done(server, polled){
+                return;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn methods(received:&Receiver<Value>) -> Vec<String>{
+        received.try_iter().map(|message| message["method"].as_str().unwrap_or_default().to_string()).collect()
+    }
+
+    fn document() -> PathBuf{
+        env::temp_dir().join("lsp-test.rs")
+    }
+
+    #[test]
+    fn initializes_without_waiting(){
+        let (mut server, received) = fake(|message| Some(standard(message)));
+        assert!(!server.ready());
+        // Nothing but `initialize` may go out before the answer.
+        server.sync(&document(), "rust", 1, || "fn main(){}".into()).unwrap();
+        let hover = server.request_at("textDocument/hover", Position{line:0, character:0}, json!({}));
+        assert_eq!(hover.unwrap_err().to_string(), "still starting");
+
+        poll_until(&mut server, |server, polled| polled.is_ok() && server.ready());
+        server.sync(&document(), "rust", 1, || "fn main(){}".into()).unwrap();
+        poll_until(&mut server, |_, polled| polled.unwrap());
+        assert_eq!(methods(&received), ["initialize", "initialized", "textDocument/didOpen"]);
+    }
+
+    #[test]
+    fn reads_published_diagnostics(){
+        let (mut server, _received) = fake(|message| Some(standard(message)));
+        poll_until(&mut server, |server, _| server.ready());
+        server.sync(&document(), "rust", 1, || "ok\nlet foo".into()).unwrap();
+        poll_until(&mut server, |_, polled| polled.unwrap());
+
+        let diagnostics = &server.diagnostics;
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].start == Position{line:0, character:0});
+        assert!(diagnostics[0].severity == Severity::Error);
+        assert_eq!(diagnostics[1].message, "unused");
+        assert!(diagnostics[1].severity == Severity::Warning);
+        assert!(diagnostics[1].end == Position{line:1, character:7});
+    }
+
+    #[test]
+    fn answers_hover_definition_and_rename(){
+        let (mut server, _received) = fake(|message| Some(standard(message)));
+        poll_until(&mut server, |server, _| server.ready());
+        server.sync(&document(), "rust", 1, || "fn foo(){}".into()).unwrap();
+        let at = Position{line:0, character:4};
+
+        let hover = server.request_at("textDocument/hover", at, json!({})).unwrap();
+        assert_eq!(hover_text(&hover).as_deref(), Some("fn foo()"));
+
+        let definition = server.request_at("textDocument/definition", at, json!({})).unwrap();
+        let locations = locations(&definition);
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].path, Path::new("/src/a b.rs"));
+        assert!(locations[0].start == Position{line:3, character:5});
+
+        let rename = server.request_at("textDocument/rename", at, json!({"newName": "bar"})).unwrap();
+        let mut edits = workspace_edits(&rename);
+        assert_eq!(edits.len(), 1);
+        let mut rows = vec!["fn foo(){}".to_string()];
+        apply_edits(&mut rows, edits.remove(0).1);
+        assert_eq!(rows, ["fn bar(){}"]);
+    }
+
+    #[test]
+    fn notices_the_server_dying(){
+        let (mut server, _received) = fake(|message| {
+            if message["method"] == "textDocument/hover" {None} else {Some(standard(message))}
+        });
+        poll_until(&mut server, |server, _| server.ready());
+        server.sync(&document(), "rust", 1, String::new).unwrap();
+        let hover = server.request_at("textDocument/hover", Position{line:0, character:0}, json!({}));
+        assert_eq!(hover.unwrap_err().to_string(), "language server exited");
+        assert!(server.poll().is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_messages(){
+        let (mut server, _received) = fake(|_| Some(vec![format!("Content-Length: {}\r\n\r\n{{", usize::MAX)]));
+        let mut error = None;
+        poll_until(&mut server, |_, polled| {
+            error = polled.err();
+            error.is_some()
+        });
+        assert!(error.unwrap().to_string().starts_with("protocol error"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notices_a_server_process_exiting(){
+        // It reads a little first, so that the initialize request gets through.
+        let mut server = Server::start("head -c 1 >/dev/null", Path::new("/")).unwrap();
+        poll_until(&mut server, |_, polled| polled.is_err());
+    }
+}
@@ -1,48 +1,360 @@
-use std::fmt::format;
 use std::io::stdout;
-use std::io::Write;
-use std::{cmp , env , fs , io};
+use std::io::{IsTerminal, Read, Write};
+use std::{cmp , env , fs , io , mem , panic , thread};
 use std::cmp::Ordering; 
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool};
+use std::time::{Duration, Instant, SystemTime};
 
-use crossterm::event::{Event, KeyCode, KeyEvent , KeyModifiers};
+use crossterm::event::{Event, KeyCode, KeyEvent , KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
 use crossterm::{event, terminal , execute , cursor , queue , style};
 
 use crossterm::terminal::ClearType;
 
+use ropey::{Rope, RopeBuilder};
+use serde_json::{json, Value};
+
+mod completion;
+mod diagnostics;
+mod encoding;
+mod hex;
+mod lsp;
+mod merge;
+mod quickfix;
+mod save;
+mod shell;
+mod watch;
+use completion::Popup;
+use diagnostics::{Diagnostic, Diagnostics, Source};
+use encoding::{Decoder, Encoding};
+use hex::HexBuffer;
+use watch::Watch;
+
 fn main() -> crossterm::Result<()> {
 
+    // In a pipeline the screen goes to the terminal and the buffer to the
+    // real stdout on quit.
+    let filter = redirect_stdout()?;
+    install_panic_hook();
     let _cleanup = CleanUp;
 
-    let _stdio = terminal::enable_raw_mode()?;
+    let mut config = Config::load();
+    let mut file = None;
+    for arg in env::args().skip(1){
+        match arg.as_str(){
+            "--readonly" | "-R" => config.read_only = true,
+            _ => file = file.or(Some(arg)),
+        }
+    }
+    // Like vi, the editor runs as a pager when invoked as `view`.
+    if env::args().next().is_some_and(|name| Path::new(&name).file_name().is_some_and(|name| name == "view")){
+        config.read_only = true;
+    }
+    setup_terminal(&config)?;
 
-    let mut editor = Editor::new();
+    let mut editor = Editor::new(config);
+    editor.filter = filter.is_some();
+    if file.as_deref() == Some("-"){
+        editor.open_stdin()?;
+    }else if let Some(file) = file{
+        if !editor.open(file.into(), None)?{
+            return Ok(());
+        }
+    }
 
-    while editor.run()?{}
+    // Unsaved work is written out before a panic is let through.
+    let running = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        while editor.run()?{}
+        crossterm::Result::Ok(())
+    }));
+    match running{
+        Ok(result) => result?,
+        Err(panic) => {
+            editor.output.show_file();
+            editor.output.write_recovery();
+            panic::resume_unwind(panic);
+        },
+    }
+    if let Some(pipe) = filter{
+        editor.output.show_file();
+        editor.output.write_buffer(pipe)?;
+    }
     Ok(())
     
 }
 
+/// When stdout is not a terminal, as in `git log | editor - | mail`, keeps
+/// the original stdout aside and points it at the terminal instead, so that
+/// drawing is unaffected. Returns the original for the buffer to go to.
+#[cfg(unix)]
+fn redirect_stdout() -> io::Result<Option<fs::File>>{
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    if io::stdout().is_terminal(){
+        return Ok(None);
+    }
+    let tty = fs::OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    unsafe{
+        let pipe = libc::dup(libc::STDOUT_FILENO);
+        if pipe < 0 || libc::dup2(tty.as_raw_fd(), libc::STDOUT_FILENO) < 0{
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(fs::File::from_raw_fd(pipe)))
+    }
+}
+
+#[cfg(not(unix))]
+fn redirect_stdout() -> io::Result<Option<fs::File>>{
+    Ok(None)
+}
+
+/// Hands the terminal back to the shell and stops the process, as Ctrl-Z
+/// does outside raw mode, then takes the terminal over again once the shell
+/// resumes it. Returns `false` where there is no job control to do this.
+#[cfg(unix)]
+fn suspend(config:&Config) -> crossterm::Result<bool>{
+    restore_terminal();
+    unsafe{
+        libc::raise(libc::SIGTSTP);
+    }
+    setup_terminal(config)?;
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn suspend(_config:&Config) -> crossterm::Result<bool>{
+    Ok(false)
+}
+
+/// Set while the editor draws on the alternate screen, for `restore_terminal`
+/// to leave it, including from the panic hook.
+static ALTERNATE_SCREEN:AtomicBool = AtomicBool::new(false);
+
+/// Raw mode, plus the alternate screen and mouse capture if configured.
+fn setup_terminal(config:&Config) -> crossterm::Result<()>{
+    terminal::enable_raw_mode()?;
+    if config.alternate_screen{
+        execute!(stdout(), terminal::EnterAlternateScreen)?;
+        ALTERNATE_SCREEN.store(true, atomic::Ordering::Relaxed);
+    }
+    if config.mouse {
+        execute!(stdout(), event::EnableMouseCapture)?;
+    }
+    execute!(stdout(), event::EnableFocusChange)?;
+    Ok(())
+}
+
+/// Undoes `setup_terminal`, bringing back what was on screen before if the
+/// alternate screen was used. Errors are ignored, as this also runs while
+/// panicking.
+fn restore_terminal(){
+    let _ = terminal::disable_raw_mode();
+    let _ = execute!(stdout(), event::DisableMouseCapture, event::DisableFocusChange, cursor::Show);
+    if ALTERNATE_SCREEN.swap(false, atomic::Ordering::Relaxed){
+        let _ = execute!(stdout(), terminal::LeaveAlternateScreen);
+    }else{
+        let _ = Output::clear_screen();
+    }
+}
+
+/// Puts the terminal back before the default hook prints the panic, so the
+/// message and any backtrace are readable and the shell is usable afterwards.
+/// Only a panic on the main thread ends the editor; one in a helper thread,
+/// such as a language server reader, leaves the terminal to the main loop.
+fn install_panic_hook(){
+    let default_hook = panic::take_hook();
+    let main_thread = thread::current().id();
+    panic::set_hook(Box::new(move |info| {
+        if thread::current().id() == main_thread{
+            restore_terminal();
+        }
+        default_hook(info);
+    }));
+}
+
 struct CleanUp;
 
 impl Drop for CleanUp{
     fn drop(&mut self) {
-        terminal::disable_raw_mode().expect("Could not disable raw mode");
-        Output::clear_screen().expect("Error");
+        // The panic hook has done this already, and clearing the screen
+        // again would wipe the message it printed.
+        if !thread::panicking(){
+            restore_terminal();
+        }
     }
 }
 
 struct Reader;
 
+const IDLE_TICK:Duration = Duration::from_millis(500);
+
 impl Reader{
+    /// Waits up to `IDLE_TICK` for input; `None` means nothing happened.
+    /// Key releases, which some platforms report too, are skipped.
+    fn read_event(&self) -> crossterm::Result<Option<Event>>{
+        if !event::poll(IDLE_TICK)?{
+            return Ok(None);
+        }
+        match event::read()?{
+            Event::Key(KeyEvent{kind:KeyEventKind::Release, ..}) => Ok(None),
+            event => Ok(Some(event)),
+        }
+    }
+
     fn read_key(&self) -> crossterm::Result<KeyEvent>{
         loop{
-            if event::poll(Duration::from_millis(500))?{
-                if let Event::Key(event) = event::read() ? {
-                    return Ok(event);
-                }
+            if let Some(Event::Key(event)) = self.read_event()? {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+/// Settings read from `~/.editorrc`, one `key = value` pair per line.
+/// Lines starting with `#` and unknown keys are ignored.
+struct Config{
+    mouse:bool,
+    word_chars:String,
+    expand_tab:bool,
+    indent_width:usize,
+    tab_stop:usize,
+    large_file_mb:u64,
+    /// Seconds of idle time before a modified buffer is saved; 0 turns it off.
+    autosave:u64,
+    alternate_screen:bool,
+    /// Word length at which completions pop up while typing; 0 waits for Ctrl-N.
+    complete_after:usize,
+    /// Commands run over the buffer on save, by file type name; from
+    /// `formatter.<name>` keys.
+    formatters:BTreeMap<String, String>,
+    /// Build command run by Alt-M.
+    make:String,
+    /// Language server commands by file type name; from `lsp.<name>` keys.
+    servers:BTreeMap<String, String>,
+    /// Commands run on the file after it is saved, by file type name; from
+    /// `linter.<name>` keys.
+    linters:BTreeMap<String, String>,
+    /// Open every buffer read-only; set by `--readonly` rather than the file.
+    read_only:bool
+}
+
+fn parse_bool(value:&str) -> bool{
+    matches!(value, "true" | "on" | "yes" | "1")
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CharClass{
+    Whitespace,
+    Word,
+    Punctuation
+}
+
+impl Default for Config{
+    fn default() -> Self{
+        Self{
+            mouse:false,
+            word_chars:"_".into(),
+            expand_tab:false,
+            indent_width:4,
+            tab_stop:TAB_STOP,
+            large_file_mb:256,
+            autosave:0,
+            alternate_screen:true,
+            complete_after:0,
+            formatters:BTreeMap::new(),
+            make:"make".into(),
+            servers:BTreeMap::new(),
+            linters:BTreeMap::new(),
+            read_only:false
+        }
+    }
+}
+
+impl Config{
+    fn load() -> Self{
+        let mut config = Self::default();
+        let contents = env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".editorrc"))
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default();
+
+        for line in contents.lines().map(str::trim){
+            if line.is_empty() || line.starts_with('#'){
+                continue;
             }
+            if let Some((key, value)) = line.split_once('='){
+                config.set(key.trim(), value.trim());
+            }
+        }
+        config
+    }
+
+    fn set(&mut self, key:&str, value:&str){
+        match key{
+            "mouse" => self.mouse = parse_bool(value),
+            "word_chars" => self.word_chars = value.into(),
+            "expand_tab" => self.expand_tab = parse_bool(value),
+            "indent_width" => {
+                if let Ok(width) = value.parse::<usize>(){
+                    self.indent_width = cmp::max(width, 1);
+                }
+            },
+            "large_file_mb" => {
+                if let Ok(size) = value.parse::<u64>(){
+                    self.large_file_mb = size;
+                }
+            },
+            "tab_stop" => {
+                if let Ok(width) = value.parse::<usize>(){
+                    self.tab_stop = cmp::max(width, 1);
+                }
+            },
+            "alternate_screen" => self.alternate_screen = parse_bool(value),
+            "make" => self.make = value.into(),
+            "complete_after" => {
+                if let Ok(length) = value.parse::<usize>(){
+                    self.complete_after = length;
+                }
+            },
+            "autosave" => {
+                if let Ok(seconds) = value.parse::<u64>(){
+                    self.autosave = seconds;
+                }
+            },
+            _ => {
+                if let Some(file_type) = key.strip_prefix("formatter."){
+                    self.formatters.insert(file_type.trim().into(), value.into());
+                }else if let Some(file_type) = key.strip_prefix("lsp."){
+                    self.servers.insert(file_type.trim().into(), value.into());
+                }else if let Some(file_type) = key.strip_prefix("linter."){
+                    self.linters.insert(file_type.trim().into(), value.into());
+                }
+            },
+        }
+    }
+
+    /// What one level of indentation inserts: a tab, or `indent_width` spaces.
+    fn indent_unit(&self) -> String{
+        if self.expand_tab{
+            " ".repeat(self.indent_width)
+        }else{
+            "\t".into()
+        }
+    }
+
+    /// Alphanumerics and anything listed in `word_chars` make up words;
+    /// word movement stops wherever the class changes.
+    fn char_class(&self, c:char) -> CharClass{
+        if c.is_whitespace(){
+            CharClass::Whitespace
+        }else if c.is_alphanumeric() || self.word_chars.contains(c){
+            CharClass::Word
+        }else{
+            CharClass::Punctuation
         }
     }
 }
@@ -53,234 +365,1631 @@ struct Output{
     cursor:CursorController,
     editor_rows:EditorRows,
     status_message:StatusMessage,
-    dirty:u64
+    dirty:u64,
+    last_click:Option<(Instant, (usize, usize))>,
+    config:Config,
+    history:History,
+    screen:Screen,
+    /// The bytes being edited while in hex mode; the buffer then holds their dump.
+    hex:Option<HexBuffer>,
+    last_search:Option<String>,
+    /// The other buffer when both a file and command output are open.
+    stashed:Option<StashedBuffer>,
+    completion:Option<Popup>
+}
+
+fn severity_color(severity:diagnostics::Severity) -> style::Color{
+    match severity{
+        diagnostics::Severity::Error => style::Color::Red,
+        diagnostics::Severity::Warning => style::Color::Yellow,
+        diagnostics::Severity::Information => style::Color::Blue,
+        diagnostics::Severity::Hint => style::Color::Cyan,
+    }
+}
+
+/// Counts the non-whitespace characters in `rows` before `(x, y)`.
+fn solid_chars_before(rows:&[String], x:usize, y:usize) -> usize{
+    let solid = |text:&str| text.chars().filter(|c| !c.is_whitespace()).count();
+    let before:usize = rows.iter().take(y).map(|row| solid(row)).sum();
+    before + rows.get(y).map_or(0, |row| solid(&row[..cmp::min(x, row.len())]))
+}
+
+/// Where the non-whitespace character numbered `count` is in `rows`, or
+/// the end of the last row if there are fewer.
+fn position_after_solid(rows:&[String], mut count:usize) -> (usize, usize){
+    for (y, row) in rows.iter().enumerate(){
+        for (x, _) in row.char_indices().filter(|(_, c)| !c.is_whitespace()){
+            if count == 0{
+                return (x, y);
+            }
+            count -= 1;
+        }
+    }
+    let last = rows.len().saturating_sub(1);
+    (rows.get(last).map_or(0, String::len), last)
+}
+
+/// Everything `Output` keeps about a buffer that is not on screen.
+struct StashedBuffer{
+    editor_rows:EditorRows,
+    cursor:CursorController,
+    history:History,
+    dirty:u64,
+    hex:Option<HexBuffer>
 }
 
 impl Output{
-    fn new() -> Self{
+    fn new(config:Config) -> Self{
         let win_size = terminal::size()
         .map(|(x,y)| (x as usize , y as usize - 2))
         .unwrap();
+        Self::with_size(config, win_size)
+    }
+
+    /// An editor for a text area of `win_size` columns and rows.
+    fn with_size(config:Config, win_size:(usize, usize)) -> Self{
+        let mut editor_rows = EditorRows::new();
+        editor_rows.set_tab_stop(config.tab_stop);
+        editor_rows.read_only = config.read_only;
+        let help = if config.read_only{
+            "HELP: Space/b = Page down/up | g/G = Top/Bottom | / = Search | n/N = Next/Previous | q = Quit"
+        }else{
+            "HELP: Ctrl-S = Save | Ctrl-Q = Quit | Ctrl-G = Go to line | Alt-U/Alt-E = Undo/Redo"
+        };
 
         Self {
             win_size,
             editor_contents:EditorContents::new(),
             cursor:CursorController::new(win_size),
-            editor_rows:EditorRows::new(),
-            status_message:StatusMessage::new("HELP: Ctrl-S = Save | Ctrl-Q = Quit ".into()),
-            dirty:0
+            editor_rows,
+            status_message:StatusMessage::new(help.into()),
+            dirty:0,
+            last_click:None,
+            config,
+            history:History::new(),
+            screen:Screen::new(),
+            hex:None,
+            last_search:None,
+            stashed:None,
+            completion:None
         }
     }
 
-    fn insert_newline(&mut self){
-        if self.cursor.cursor_x == 0{
-            self.editor_rows.insert_row(self.cursor.cursor_y, String::new());
-        }else {
-            let cur_row = self.editor_rows.get_editor_row_mut(self.cursor.cursor_y);
-            let new_row_content:String = cur_row.row_contents[self.cursor.cursor_x..].into();
+    /// Swaps in a freshly loaded buffer, resetting everything tied to the old one.
+    fn replace_buffer(&mut self, mut editor_rows:EditorRows){
+        editor_rows.set_tab_stop(self.config.tab_stop);
+        // A reload of the same file must not look unchanged to a language server.
+        editor_rows.revision = self.editor_rows.revision + 1;
+        self.editor_rows = editor_rows;
+        self.cursor = CursorController::new(self.win_size);
+        self.history = History::new();
+        self.dirty = 0;
+        self.hex = None;
+        self.completion = None;
+    }
+
+    /// Takes the buffer off screen, leaving an empty one in its place.
+    fn take_buffer(&mut self) -> StashedBuffer{
+        StashedBuffer{
+            editor_rows:mem::replace(&mut self.editor_rows, EditorRows::new()),
+            cursor:mem::replace(&mut self.cursor, CursorController::new(self.win_size)),
+            history:mem::replace(&mut self.history, History::new()),
+            dirty:mem::take(&mut self.dirty),
+            hex:self.hex.take()
+        }
+    }
 
-            cur_row.row_contents.truncate(self.cursor.cursor_x);
-            EditorRows::render_row(cur_row);
+    fn put_buffer(&mut self, buffer:StashedBuffer){
+        self.editor_rows = buffer.editor_rows;
+        self.cursor = buffer.cursor;
+        // The terminal may have been resized while it was put aside.
+        self.cursor.screen_columns = self.win_size.0;
+        self.cursor.screen_rows = self.win_size.1;
+        self.history = buffer.history;
+        self.dirty = buffer.dirty;
+        self.hex = buffer.hex;
+    }
 
-            self.editor_rows.insert_row(self.cursor.cursor_y+1, new_row_content)
+    /// Alt-B: switches between the file and the command output.
+    fn switch_buffers(&mut self){
+        if !self.swap_buffers(){
+            self.status_message.set_message("No command output to switch to; Alt-! runs a command".into());
         }
-        self.cursor.cursor_x = 0;
-        self.cursor.cursor_y += 1;
-        self.dirty += 1;
     }
 
-    fn insert_char(&mut self , ch:char){
-        if self.cursor.cursor_y == self.editor_rows.num_rows(){
-            self.editor_rows.insert_row(self.editor_rows.num_rows(), String::new());
-            self.dirty += 1;
+    /// Puts the other buffer on screen; `false` if there is only one.
+    fn swap_buffers(&mut self) -> bool{
+        match self.stashed.take(){
+            Some(other) => {
+                self.stashed = Some(self.take_buffer());
+                self.put_buffer(other);
+                true
+            },
+            None => false,
         }
-        self.editor_rows.get_editor_row_mut(self.cursor.cursor_y).insert_char(self.cursor.cursor_x, ch);
+    }
 
-        self.cursor.cursor_x += 1;
-        self.dirty += 1;
+    /// Shows `rows` as the scratch buffer, replacing any earlier command
+    /// output. The file is put aside for Alt-B to return to.
+    fn show_scratch(&mut self, mut rows:EditorRows){
+        if !self.editor_rows.scratch{
+            self.stashed = Some(self.take_buffer());
+        }
+        rows.scratch = true;
+        rows.autosave = false;
+        self.replace_buffer(rows);
     }
 
-    fn delete_char(&mut self){
-        if self.cursor.cursor_y == self.editor_rows.num_rows(){
-            return;
+    /// Brings the file back on screen if command output is showing.
+    fn show_file(&mut self){
+        if self.editor_rows.scratch{
+            self.swap_buffers();
         }
-        let row = self.editor_rows.get_editor_row_mut(self.cursor.cursor_y);
+    }
 
-        if self.cursor.cursor_x > 0{
-            row.delete_char(self.cursor.cursor_x - 1);
-            self.cursor.cursor_x -= 1;
-        }else{
-            let prev_row_content = self.editor_rows.get_row(self.cursor.cursor_y - 1);
-            self.cursor.cursor_x = prev_row_content.len();
-            self.editor_rows.join_adjacent_rows(self.cursor.cursor_y);
-            self.cursor.cursor_y -= 1;
+    /// Whether the file has changes that are not saved, whether it is on
+    /// screen or not. Edits to command output don't count.
+    fn has_unsaved_changes(&self) -> bool{
+        (!self.editor_rows.scratch && self.dirty > 0)
+            || self.stashed.as_ref().is_some_and(|other| !other.editor_rows.scratch && other.dirty > 0)
+    }
+
+    /// The file's text, whether it is on screen or put aside for command
+    /// output; `None` in hex mode.
+    fn file_rows(&mut self) -> Option<&mut EditorRows>{
+        if !self.editor_rows.scratch{
+            return if self.hex.is_none() {Some(&mut self.editor_rows)} else {None};
         }
-        self.dirty += 1;
+        self.stashed
+            .as_mut()
+            .filter(|other| !other.editor_rows.scratch && other.hex.is_none())
+            .map(|other| &mut other.editor_rows)
+    }
 
+    /// Adopts a new terminal size and forgets what is on screen, so that the
+    /// next refresh redraws everything.
+    fn resize(&mut self, win_size:(usize, usize)){
+        self.win_size = win_size;
+        self.cursor.screen_columns = win_size.0;
+        self.cursor.screen_rows = win_size.1;
+        self.screen = Screen::new();
+    }
 
+    /// Switches to hex mode on `file`. The dump goes into a read-only buffer
+    /// so that the usual drawing and navigation work on it unchanged.
+    fn open_hex(&mut self, file:PathBuf) -> io::Result<()>{
+        let limit = cmp::min(self.config.large_file_mb * 1024 * 1024, PREVIEW_BYTES);
+        let read_only = self.config.read_only || !is_writable(&file);
+        let mut hex = HexBuffer::load(file, limit)?;
+        let truncated = hex.read_only;
+        hex.read_only |= read_only;
+        let dump:String = (0..hex.num_rows()).map(|at| hex.dump_row(at) + "\n").collect();
+        let mut rows = EditorRows::from_rope(Rope::from_str(&dump), Some(hex.filename.clone()));
+        rows.read_only = true;
+        self.replace_buffer(rows);
+        if truncated{
+            self.status_message.set_message(format!("Showing the first {} MiB (read-only)", limit / (1024 * 1024)));
+        }
+        self.hex = Some(hex);
+        Ok(())
     }
 
-    fn clear_screen() -> crossterm::Result<()>{
-        execute!(stdout(), terminal::Clear(ClearType::All))?;
-        execute!(stdout() , cursor::MoveTo(0,0))
+    /// Rewrites the dump from row `from` after the bytes changed, through to
+    /// the end if bytes were inserted or removed.
+    fn sync_hex(&mut self, from:usize, shifted:bool){
+        let hex = match &self.hex{
+            Some(hex) => hex,
+            None => return,
+        };
+        let old_rows = self.editor_rows.num_rows();
+        let end = if shifted {hex.num_rows()} else {cmp::min(from + 1, hex.num_rows())};
+        let count = if shifted {old_rows - from} else {cmp::min(1, old_rows - from)};
+        let rows = (from..end).map(|at| hex.dump_row(at)).collect();
+        self.editor_rows.replace_rows(from, count, rows);
+        self.place_hex_cursor();
     }
 
-    fn draw_status_bar(&mut self) {
-        self.editor_contents
-            .push_str(&style::Attribute::Reverse.to_string());
-        let info = format!(
-            "{} {} -- {} lines",
-            self.editor_rows
-                .filename
-                .as_ref()
-                .and_then(|path| path.file_name())
-                .and_then(|name| name.to_str())
-                .unwrap_or("[No Name]"), if self.dirty > 0{"(modified)"} else{""},
-            self.editor_rows.num_rows()
-        );
-        let info_len = cmp::min(info.len(), self.win_size.0);
-        let line_info = format!(
-            "{}/{}",
-            self.cursor.cursor_y + 1,
-            self.editor_rows.num_rows()
-        );
-        self.editor_contents.push_str(&info[..info_len]);
-        for i in info_len..self.win_size.0 {
-            if self.win_size.0 - i == line_info.len() {
-                self.editor_contents.push_str(&line_info);
-                break;
-            } else {
-                self.editor_contents.push(' ')
-            }
+    /// Puts the text cursor on the nibble the hex cursor is at.
+    fn place_hex_cursor(&mut self){
+        if let Some(hex) = &self.hex{
+            (self.cursor.cursor_x, self.cursor.cursor_y) = hex.position();
+            self.cursor.anchor = None;
         }
-        self.editor_contents
-            .push_str(&style::Attribute::Reset.to_string());
-        self.editor_contents.push_str("\r\n");
     }
 
-    fn draw_message_bar(&mut self) {
-        queue!(
-            self.editor_contents,
-            terminal::Clear(ClearType::UntilNewLine)
-        ).unwrap();
-        
-        if let Some(msg) = self.status_message.message(){
-            self.editor_contents.push_str(&msg[..cmp::min(self.win_size.0, msg.len())]);
+    /// Moves the hex cursor to whatever byte the text cursor was moved to by
+    /// keys and clicks that know nothing about hex mode.
+    fn follow_hex_cursor(&mut self){
+        if let Some(hex) = &mut self.hex{
+            let (x, y) = (self.cursor.cursor_x, self.cursor.cursor_y);
+            if (x, y) != hex.position(){
+                hex.move_to(hex.byte_at(x, y));
+                self.place_hex_cursor();
+            }
         }
     }
 
-    fn add_rows(&mut self) {
-        let screen_rows = self.win_size.1;
-        let screen_columns = self.win_size.0;
-        for i in 0..screen_rows {
-            let file_row = i + self.cursor.row_offset;
-            if file_row >= self.editor_rows.num_rows() {
-                if self.editor_rows.num_rows() == 0 && i == screen_rows / 3 {
-                    let mut welcome = String::from("Rust Editor --- Version 1");
-                    if welcome.len() > screen_columns {
-                        welcome.truncate(screen_columns)
-                    }
-                    let mut padding = (screen_columns - welcome.len()) / 2;
-                    if padding != 0 {
-                        self.editor_contents.push('~');
-                        padding -= 1
-                    }
-                    (0..padding).for_each(|_| self.editor_contents.push(' '));
-                    self.editor_contents.push_str(&welcome);
-                } else {
-                    self.editor_contents.push('~');
+    /// Handles the keys that mean something different on bytes; returns
+    /// `false` for anything left to the normal key handling.
+    fn process_hex_key(&mut self, key:KeyEvent) -> bool{
+        let hex = match &mut self.hex{
+            Some(hex) => hex,
+            None => return false,
+        };
+        let edit = match key{
+            KeyEvent{
+                code:KeyCode::Char(c),
+                modifiers:KeyModifiers::NONE | KeyModifiers::SHIFT,
+                ..
+            } if c.is_ascii_hexdigit() => true,
+            KeyEvent{
+                code:KeyCode::Insert | KeyCode::Delete | KeyCode::Backspace,
+                modifiers:KeyModifiers::NONE,
+                ..
+            } => true,
+            KeyEvent{
+                code:KeyCode::Left | KeyCode::Right,
+                modifiers:KeyModifiers::NONE,
+                ..
+            } => false,
+            _ => return false,
+        };
+        // A read-only dump is edited by nothing, so the keys go on to the
+        // normal handling, where the pager keys are.
+        if edit && hex.read_only{
+            return false;
+        }
+
+        let (row, len) = (hex.cursor / hex::ROW_BYTES, hex.bytes.len());
+        // Whether the bytes changed; deleting past either end does nothing.
+        let changed = match key.code{
+            KeyCode::Char(c) => {
+                hex.set_nibble(c.to_digit(16).unwrap() as u8);
+                true
+            },
+            KeyCode::Insert => {
+                hex.insert_byte();
+                true
+            },
+            KeyCode::Delete => hex.delete_byte(),
+            KeyCode::Backspace => {
+                if hex.cursor > 0{
+                    hex.move_to(hex.cursor - 1);
+                    hex.delete_byte()
+                }else{
+                    false
                 }
-            } else {
-                let row = self.editor_rows.get_render(file_row);
-                let col_offset = self.cursor.col_offset;
+            },
+            KeyCode::Left => {
+                if hex.low_nibble{
+                    hex.low_nibble = false;
+                }else{
+                    hex.move_to(hex.cursor.saturating_sub(1));
+                }
+                false
+            },
+            _ => {
+                hex.move_to(hex.cursor + 1);
+                false
+            },
+        };
 
-                let len = cmp::min(row.len().saturating_sub(col_offset) , screen_columns);
-                let start = if len == 0{0} else{col_offset};
-                self.editor_contents.push_str(&row[start..start + len]);
-            }
-            queue!(
-                self.editor_contents,
-                terminal::Clear(ClearType::UntilNewLine)
-            )
-            .unwrap();
-            self.editor_contents.push_str("\r\n");
-            
+        if changed{
+            let shifted = hex.bytes.len() != len;
+            let from = cmp::min(row, hex.cursor / hex::ROW_BYTES);
+            self.dirty += 1;
+            self.sync_hex(from, shifted);
+        }else{
+            self.place_hex_cursor();
         }
+        true
     }
 
-    fn refresh_screen(&mut self) ->crossterm::Result<()>{
-        self.cursor.scroll(&self.editor_rows);
-        queue!(self.editor_contents, cursor::Hide ,cursor::MoveTo(0,0))?;
-        self.add_rows();
-        self.draw_status_bar();
-        self.draw_message_bar();
-        
-        let cursor_x = self.cursor.render_x - self.cursor.col_offset;
-
-        let cursor_y = self.cursor.cursor_y - self.cursor.row_offset ;
-        
-        queue!(self.editor_contents, cursor::MoveTo(cursor_x  as u16,cursor_y  as u16) ,cursor::Show)?;
-        self.editor_contents.flush()
+    /// Moves to the next match of a hex byte pattern, wrapping around.
+    fn find_hex(&mut self, input:&str){
+        let hex = match &mut self.hex{
+            Some(hex) => hex,
+            None => return,
+        };
+        match hex::parse_pattern(input){
+            None => self.status_message.set_message(format!("Invalid hex pattern: {}", input)),
+            Some(pattern) => match hex.find(&pattern){
+                Some(at) => {
+                    hex.move_to(at);
+                    self.place_hex_cursor();
+                },
+                None => self.status_message.set_message(format!("Not found: {}", input)),
+            },
+        }
     }
 
-    fn move_cursor(&mut self,direction:KeyCode) {
-        self.cursor.move_cursor(direction , &self.editor_rows);
+    /// Folds the version of the file on disk into the buffer, with each
+    /// difference left as a conflict block to resolve. Undoable as one change.
+    fn merge_with_disk(&mut self) -> io::Result<()>{
+        let file = match &self.editor_rows.filename{
+            Some(file) => file.clone(),
+            None => return Ok(()),
+        };
+        let rows = &self.editor_rows;
+        let (disk, _) = EditorRows::load(file, rows.encoding, rows.bom, None, |_| Ok(()))?;
+        let mine:Vec<String> = (0..rows.num_rows()).map(|at| rows.get_row(at).into_owned()).collect();
+        let theirs:Vec<String> = (0..disk.num_rows()).map(|at| disk.get_row(at).into_owned()).collect();
+        let merged = merge::conflict_merge(&mine, &theirs);
+
+        self.record_change(0, mine.len(), merged.len());
+        self.editor_rows.replace_rows(0, mine.len(), merged);
+        self.editor_rows.disk = disk.disk;
+        self.editor_rows.watch = disk.watch;
+        self.completion = None;
+        self.cursor.jump_to(0, cmp::min(self.cursor.cursor_y, self.editor_rows.num_rows()));
+        self.dirty += 1;
+        self.status_message.set_message("Merged with the file on disk; resolve the <<<<<<< blocks and save".into());
+        Ok(())
     }
-}
 
-struct CursorController {
-    cursor_x: usize,
-    cursor_y: usize,
-    screen_columns:usize,
-    screen_rows:usize,
-    row_offset:usize,
-    col_offset:usize,
-    render_x: usize,
-    
-}
+    /// Last resort after a panic: saves a modified buffer beside its file, or
+    /// in the temporary directory, and says where on stderr. It is written
+    /// as a save would write it, in the file's encoding and line endings.
+    fn write_recovery(&self){
+        if self.dirty == 0{
+            return;
+        }
+        let name = self.editor_rows.filename.as_ref().and_then(|path| path.file_name()).map_or_else(
+            || format!("editor-{}", std::process::id()),
+            |name| name.to_string_lossy().into_owned()
+        );
+        let beside = self.editor_rows.filename.as_ref().and_then(|path| path.parent());
+        let candidates = beside.into_iter().map(Path::to_path_buf).chain([env::temp_dir()]);
 
-impl CursorController{
-    fn new(win_size:(usize , usize)) -> Self{
-        Self{
-            cursor_x:0,
-            cursor_y:0,
-            screen_columns: win_size.0,
-            screen_rows: win_size.1,
-            row_offset:0,
-            col_offset:0,
-            render_x:0
+        for dir in candidates{
+            // An earlier dump is kept; this one takes the next free name.
+            for attempt in 0..100{
+                let path = match attempt{
+                    0 => dir.join(format!("{}.recovered", name)),
+                    _ => dir.join(format!("{}.{}.recovered", name, attempt)),
+                };
+                let file = match fs::OpenOptions::new().write(true).create_new(true).open(&path){
+                    Ok(file) => file,
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                    Err(_) => break,
+                };
+                let written = match &self.hex{
+                    Some(hex) => io::BufWriter::new(file).write_all(&hex.bytes),
+                    None => self.editor_rows.write_to(file).map(|_| ()),
+                };
+                match written{
+                    Ok(()) => {
+                        eprintln!("Unsaved changes were written to {}", path.display());
+                        return;
+                    },
+                    Err(_) => {
+                        let _ = fs::remove_file(&path);
+                        break;
+                    },
+                }
+            }
         }
+        eprintln!("Unsaved changes could not be recovered");
     }
 
-    fn scroll(&mut self, editor_rows: &EditorRows){
-        self.render_x = 0;
-        if self.cursor_y < editor_rows.num_rows(){
-            self.render_x = self.get_render_x(editor_rows.get_editor_row(self.cursor_y))
+    /// Hands the buffer on to the next command in a pipeline; hex mode
+    /// passes the raw bytes.
+    fn write_buffer(&self, pipe:fs::File) -> io::Result<()>{
+        match &self.hex{
+            Some(hex) => io::BufWriter::new(pipe).write_all(&hex.bytes),
+            None => {
+                self.editor_rows.check_encodable()?;
+                self.editor_rows.write_to(pipe).map(|_| ())
+            },
         }
+    }
 
-        self.row_offset = cmp::min(self.row_offset , self.cursor_y);
+    fn autosave_active(&self) -> bool{
+        self.config.autosave > 0 && self.editor_rows.autosave && self.editor_rows.filename.is_some()
+            && self.hex.is_none() && !self.is_read_only()
+    }
 
-        if self.cursor_y >= self.row_offset + self.screen_rows{
-            self.row_offset = self.cursor_y - self.screen_rows + 1;
+    fn is_read_only(&self) -> bool{
+        match &self.hex{
+            Some(hex) => hex.read_only,
+            None => self.editor_rows.read_only,
         }
+    }
 
-        self.col_offset = cmp::min(self.col_offset, self.render_x);
+    fn check_writable(&mut self) -> bool{
+        if self.editor_rows.read_only{
+            self.status_message.set_message("Buffer is read-only".into());
+        }
+        !self.editor_rows.read_only
+    }
 
-        if self.render_x >= self.col_offset + self.screen_columns{
-            self.col_offset = self.render_x - self.screen_columns + 1;
+    /// Shows `message` and waits for one of the keys in `choices`; `None` on Esc.
+    fn choose(&mut self, message:String, choices:&str) -> crossterm::Result<Option<char>>{
+        loop{
+            self.status_message.set_message(message.clone());
+            self.refresh_screen()?;
+            match Reader.read_key()?{
+                KeyEvent{
+                    code:KeyCode::Esc,
+                    ..
+                } => {
+                    self.status_message.set_message(String::new());
+                    return Ok(None);
+                },
+                KeyEvent{
+                    code:KeyCode::Char(choice),
+                    ..
+                } if choices.contains(choice) => {
+                    self.status_message.set_message(String::new());
+                    return Ok(Some(choice));
+                },
+                _ => {}
+            }
         }
+    }
 
+    /// Snapshots rows `at..at + old_len` before they are replaced by `new_len` rows.
+    fn record_change(&mut self, at:usize, old_len:usize, new_len:usize){
+        let cursor = (self.cursor.cursor_x, self.cursor.cursor_y);
+        self.history.record(&self.editor_rows, at, old_len, new_len, cursor, false);
     }
 
-    fn move_cursor(&mut self, direction: KeyCode , editor_rows: &EditorRows) {
-        let num_rows = editor_rows.num_rows();
+    /// Like `record_change` for an edit inside one row; consecutive edits of
+    /// the same row are undone together.
+    fn record_typing(&mut self, at:usize){
+        let cursor = (self.cursor.cursor_x, self.cursor.cursor_y);
+        self.history.record(&self.editor_rows, at, 1, 1, cursor, true);
+    }
+
+    fn undo(&mut self, redo:bool){
+        if !self.check_writable(){
+            return;
+        }
+        let cursor = (self.cursor.cursor_x, self.cursor.cursor_y);
+        let restored = if redo{
+            self.history.redo(&mut self.editor_rows, cursor)
+        }else{
+            self.history.undo(&mut self.editor_rows, cursor)
+        };
+        match restored{
+            Some((cursor_x, cursor_y)) => {
+                self.cursor.anchor = None;
+                self.cursor.cursor_y = cmp::min(cursor_y, self.editor_rows.num_rows());
+                self.cursor.cursor_x = cursor_x;
+                self.cursor.clamp_x(&self.editor_rows);
+                self.dirty += 1;
+            },
+            None => self.status_message.set_message(
+                if redo {"Nothing to redo"} else {"Nothing to undo"}.into()
+            ),
+        }
+    }
+
+    fn insert_newline(&mut self){
+        if !self.check_writable(){
+            return;
+        }
+        self.delete_selection();
+        if self.cursor.cursor_x == 0{
+            self.record_change(self.cursor.cursor_y, 0, 1);
+            self.editor_rows.insert_row(self.cursor.cursor_y, String::new());
+        }else {
+            self.record_change(self.cursor.cursor_y, 1, 2);
+            self.editor_rows.split_row(self.cursor.cursor_y, self.cursor.cursor_x);
+        }
+        self.cursor.cursor_x = 0;
+        self.cursor.cursor_y += 1;
+        self.dirty += 1;
+    }
+
+    fn insert_char(&mut self , ch:char){
+        if !self.check_writable(){
+            return;
+        }
+        self.delete_selection();
+        if self.cursor.cursor_y == self.editor_rows.num_rows(){
+            self.record_change(self.cursor.cursor_y, 0, 1);
+            self.editor_rows.insert_row(self.editor_rows.num_rows(), String::new());
+            self.dirty += 1;
+        }else{
+            self.record_typing(self.cursor.cursor_y);
+        }
+        self.editor_rows.insert_char(self.cursor.cursor_y, self.cursor.cursor_x, ch);
+
+        self.cursor.cursor_x += ch.len_utf8();
+        self.dirty += 1;
+    }
+
+    fn delete_char(&mut self){
+        if !self.check_writable(){
+            return;
+        }
+        if self.delete_selection(){
+            return;
+        }
+        if self.cursor.cursor_y == self.editor_rows.num_rows()
+            || (self.cursor.cursor_x == 0 && self.cursor.cursor_y == 0){
+            return;
+        }
+
+        if self.cursor.cursor_x > 0{
+            self.record_typing(self.cursor.cursor_y);
+            let row = self.editor_rows.get_row(self.cursor.cursor_y);
+            let prev = row[..self.cursor.cursor_x].chars().next_back().map_or(1, char::len_utf8);
+            self.editor_rows.delete_char(self.cursor.cursor_y, self.cursor.cursor_x - prev);
+            self.cursor.cursor_x -= prev;
+        }else{
+            self.record_change(self.cursor.cursor_y - 1, 2, 1);
+            let prev_row_content = self.editor_rows.get_row(self.cursor.cursor_y - 1);
+            self.cursor.cursor_x = prev_row_content.len();
+            self.editor_rows.join_adjacent_rows(self.cursor.cursor_y);
+            self.cursor.cursor_y -= 1;
+        }
+        self.dirty += 1;
+
+
+    }
+
+    fn delete_word(&mut self, forward:bool){
+        if !self.check_writable(){
+            return;
+        }
+        if self.delete_selection() || self.cursor.cursor_y == self.editor_rows.num_rows(){
+            return;
+        }
+        let start = (self.cursor.cursor_x, self.cursor.cursor_y);
+        self.cursor.move_word(forward, &self.editor_rows, &self.config);
+        if self.cursor.cursor_y == self.editor_rows.num_rows(){
+            self.cursor.cursor_x = start.0;
+            self.cursor.cursor_y = start.1;
+            return;
+        }
+        self.cursor.anchor = Some(start);
+        self.delete_selection();
+    }
+
+    /// Removes the selected text, leaving the cursor where it started.
+    /// Returns `false` when there was nothing selected.
+    fn delete_selection(&mut self) -> bool{
+        let selection = self.cursor.selection();
+        self.cursor.anchor = None;
+        let ((start_x, start_y), (end_x, end_y)) = match selection{
+            Some(selection) => selection,
+            None => return false,
+        };
+
+        self.record_change(start_y, end_y - start_y + 1, 1);
+        self.editor_rows.delete_range((start_x, start_y), (end_x, end_y));
+
+        self.cursor.cursor_x = start_x;
+        self.cursor.cursor_y = start_y;
+        self.dirty += 1;
+        true
+    }
+
+    /// Rows covered by the selection, or the cursor row. A selection that
+    /// ends at the start of a row leaves that row out.
+    fn selected_lines(&self) -> Option<(usize, usize)>{
+        match self.cursor.selection(){
+            Some(((_, start_y), (end_x, end_y))) => {
+                Some((start_y, if end_x == 0 && end_y > start_y {end_y - 1} else {end_y}))
+            },
+            None if self.cursor.cursor_y < self.editor_rows.num_rows() => {
+                Some((self.cursor.cursor_y, self.cursor.cursor_y))
+            },
+            None => None,
+        }
+    }
+
+    fn rows_between(&self, first:usize, last:usize) -> Vec<String>{
+        (first..=last).map(|at| self.editor_rows.get_row(at).to_string()).collect()
+    }
+
+    /// Pipes the buffer through the formatter configured for its file type
+    /// and applies the output as one undoable change. The cursor stays in
+    /// front of the same non-blank character, as formatters mostly move
+    /// whitespace around. If the formatter fails the buffer is left alone
+    /// and the reason returned.
+    fn format_buffer(&mut self) -> Option<String>{
+        let file_type = self.editor_rows.file_type()?;
+        let command = self.config.formatters.get(file_type.name)?.clone();
+        let count = self.editor_rows.num_rows();
+        if count == 0{
+            return None;
+        }
+        let old_rows = self.rows_between(0, count - 1);
+        let mut input = old_rows.join("\n");
+        input.push('\n');
+
+        let finished = match shell::run(&command, Some(input)){
+            Ok(finished) => finished,
+            Err(err) => return Some(format!("can't run {}: {}", command, err)),
+        };
+        if !finished.success(){
+            return Some(format!("{} {}", command, finished.describe()));
+        }
+        // A formatter printing nothing is misconfigured, not asking for an empty file.
+        if finished.stdout.trim().is_empty() && !old_rows.iter().all(|row| row.trim().is_empty()){
+            return Some(format!("{} printed nothing", command));
+        }
+        let rows:Vec<String> = finished.stdout.lines().map(String::from).collect();
+        if rows == old_rows{
+            return None;
+        }
+
+        let solid = solid_chars_before(&old_rows, self.cursor.cursor_x, self.cursor.cursor_y);
+        self.record_change(0, count, rows.len());
+        let (x, y) = position_after_solid(&rows, solid);
+        self.editor_rows.replace_rows(0, count, rows);
+        self.cursor.jump_to(x, y);
+        self.dirty += 1;
+        None
+    }
+
+    /// Shifts the cursor and any selection anchor by the same number of rows.
+    fn shift_cursor_rows(&mut self, up:bool, count:usize){
+        let shift = |y:usize| if up {y - count} else {y + count};
+        self.cursor.cursor_y = shift(self.cursor.cursor_y);
+        if let Some((x, y)) = self.cursor.anchor{
+            self.cursor.anchor = Some((x, shift(y)));
+        }
+    }
+
+    fn move_lines(&mut self, up:bool){
+        if !self.check_writable(){
+            return;
+        }
+        let (first, last) = match self.selected_lines(){
+            Some(lines) => lines,
+            None => return,
+        };
+        if (up && first == 0) || (!up && last + 1 >= self.editor_rows.num_rows()){
+            return;
+        }
+        let at = if up {first - 1} else {first};
+        let count = last - first + 2;
+        let mut rows = self.rows_between(at, at + count - 1);
+        if up{
+            rows.rotate_left(1);
+        }else{
+            rows.rotate_right(1);
+        }
+        self.record_change(at, count, count);
+        self.editor_rows.replace_rows(at, count, rows);
+        self.shift_cursor_rows(up, 1);
+        self.dirty += 1;
+    }
+
+    fn duplicate_lines(&mut self){
+        if !self.check_writable(){
+            return;
+        }
+        let (first, last) = match self.selected_lines(){
+            Some(lines) => lines,
+            None => return,
+        };
+        let rows = self.rows_between(first, last);
+        let count = rows.len();
+        self.record_change(last + 1, 0, count);
+        self.editor_rows.replace_rows(last + 1, 0, rows);
+        self.shift_cursor_rows(false, count);
+        self.dirty += 1;
+    }
+
+    fn delete_lines(&mut self){
+        if !self.check_writable(){
+            return;
+        }
+        let (first, last) = match self.selected_lines(){
+            Some(lines) => lines,
+            None => return,
+        };
+        self.record_change(first, last - first + 1, 0);
+        self.editor_rows.replace_rows(first, last - first + 1, Vec::new());
+        self.cursor.anchor = None;
+        self.cursor.cursor_y = cmp::min(first, self.editor_rows.num_rows().saturating_sub(1));
+        self.cursor.clamp_x(&self.editor_rows);
+        self.dirty += 1;
+    }
+
+    /// Joins the selected rows, or the cursor row with the next one. Whitespace
+    /// around each join collapses to a single space.
+    fn join_lines(&mut self){
+        if !self.check_writable(){
+            return;
+        }
+        let (first, mut last) = match self.selected_lines(){
+            Some(lines) => lines,
+            None => return,
+        };
+        if last == first{
+            last += 1;
+        }
+        if last >= self.editor_rows.num_rows(){
+            return;
+        }
+
+        let mut joined = String::new();
+        let mut join_point = 0;
+        for (i, row) in self.rows_between(first, last).iter().enumerate(){
+            let row = if i == 0 {row.as_str()} else {row.trim_start()};
+            join_point = joined.trim_end().len();
+            joined.truncate(join_point);
+            if !joined.is_empty() && !row.is_empty(){
+                joined.push(' ');
+            }
+            joined.push_str(row);
+        }
+
+        self.record_change(first, last - first + 1, 1);
+        self.editor_rows.replace_rows(first, last - first + 1, vec![joined]);
+        self.cursor.anchor = None;
+        self.cursor.cursor_x = join_point;
+        self.cursor.cursor_y = first;
+        self.dirty += 1;
+    }
+
+    /// Opens an empty row above or below the cursor without splitting it.
+    fn open_line(&mut self, above:bool){
+        if !self.check_writable(){
+            return;
+        }
+        let num_rows = self.editor_rows.num_rows();
+        let at = cmp::min(if above {self.cursor.cursor_y} else {self.cursor.cursor_y + 1}, num_rows);
+        self.record_change(at, 0, 1);
+        self.editor_rows.replace_rows(at, 0, vec![String::new()]);
+        self.cursor.anchor = None;
+        self.cursor.cursor_x = 0;
+        self.cursor.cursor_y = at;
+        self.dirty += 1;
+    }
+
+    /// Comments out the selected rows, or uncomments them if every non-blank
+    /// one is already commented. The marker goes at the shallowest indent so
+    /// the rows stay aligned.
+    fn toggle_comment(&mut self){
+        if !self.check_writable(){
+            return;
+        }
+        let (first, last) = match self.selected_lines(){
+            Some(lines) => lines,
+            None => return,
+        };
+        let (open, close) = match self.editor_rows.file_type(){
+            Some(FileType{line_comment:Some(prefix), ..}) => (*prefix, ""),
+            Some(FileType{block_comment:Some((open, close)), ..}) => (*open, *close),
+            _ => {
+                self.status_message.set_message("No comment syntax known for this file type".into());
+                return;
+            },
+        };
+
+        let rows = self.rows_between(first, last);
+        // `/* a */ x; /* b */` is code between two comments, not one comment.
+        let is_commented = |row:&str| {
+            let row = row.trim();
+            row.len() >= open.len() + close.len() && row.starts_with(open) && row.ends_with(close)
+                && (close.is_empty() || !row[open.len()..row.len() - close.len()].contains(close))
+        };
+        let indent = rows
+            .iter()
+            .filter(|row| !row.trim().is_empty())
+            .map(|row| row.len() - row.trim_start().len())
+            .min();
+        let indent = match indent{
+            Some(indent) => indent,
+            None => return,
+        };
+        let uncomment = rows.iter().filter(|row| !row.trim().is_empty()).all(|row| is_commented(row));
+
+        // Byte column and size of the edit on each row, to keep the cursor in place.
+        let mut edits = Vec::with_capacity(rows.len());
+        let new_rows = rows.into_iter().map(|row| {
+            if row.trim().is_empty(){
+                edits.push((0, 0));
+                return row;
+            }
+            let start = row.len() - row.trim_start().len();
+            let trimmed = row.trim();
+            if uncomment{
+                let inner = &trimmed[open.len()..trimmed.len() - close.len()];
+                let body = inner.strip_prefix(' ').unwrap_or(inner);
+                edits.push((start, open.len() + inner.len() - body.len()));
+                let body = if close.is_empty() {body} else {body.strip_suffix(' ').unwrap_or(body)};
+                format!("{}{}{}", &row[..start], body, &row[start + trimmed.len()..])
+            }else{
+                edits.push((indent, open.len() + 1));
+                match close{
+                    "" => format!("{}{} {}", &row[..indent], open, &row[indent..]),
+                    _ => format!("{}{} {} {}", &row[..indent], open, row[indent..].trim_end(), close),
+                }
+            }
+        }).collect::<Vec<String>>();
+
+        self.record_change(first, last - first + 1, last - first + 1);
+        self.editor_rows.replace_rows(first, last - first + 1, new_rows);
+        self.shift_positions(first, &edits, uncomment);
+        self.dirty += 1;
+    }
+
+    /// Shifts the selected rows (or the cursor row) one indent unit right, or
+    /// left when `outdent` is set.
+    fn indent_lines(&mut self, outdent:bool){
+        if !self.check_writable(){
+            return;
+        }
+        let (first, last) = match self.selected_lines(){
+            Some(lines) => lines,
+            None => return,
+        };
+        let unit = self.config.indent_unit();
+        let mut edits = Vec::with_capacity(last - first + 1);
+        let new_rows = self.rows_between(first, last).into_iter().map(|row| {
+            let size = if outdent{
+                if row.starts_with('\t'){
+                    1
+                }else{
+                    row.chars().take(self.config.indent_width).take_while(|c| *c == ' ').count()
+                }
+            }else if row.is_empty(){
+                0
+            }else{
+                unit.len()
+            };
+            edits.push((0, size));
+            match (outdent, size){
+                (_, 0) => row,
+                (true, _) => row[size..].to_string(),
+                (false, _) => format!("{}{}", unit, row),
+            }
+        }).collect::<Vec<String>>();
+        if edits.iter().all(|(_, size)| *size == 0){
+            return;
+        }
+
+        self.record_change(first, last - first + 1, last - first + 1);
+        self.editor_rows.replace_rows(first, last - first + 1, new_rows);
+        self.shift_positions(first, &edits, outdent);
+        self.dirty += 1;
+    }
+
+    fn insert_tab(&mut self){
+        if !self.config.expand_tab{
+            return self.insert_char('\t');
+        }
+        let render_x = if self.cursor.cursor_y < self.editor_rows.num_rows(){
+            self.editor_rows.get_editor_row(self.cursor.cursor_y).cx_to_rx(self.cursor.cursor_x)
+        }else{
+            0
+        };
+        let width = self.config.indent_width;
+        (0..width - render_x % width).for_each(|_| self.insert_char(' '));
+    }
+
+    /// Keeps the cursor and selection anchor on the same text after a batch of
+    /// per-row `(byte column, size)` insertions or removals starting at row `first`.
+    fn shift_positions(&mut self, first:usize, edits:&[(usize, usize)], removed:bool){
+        let editor_rows = &self.editor_rows;
+        let shift = |(x, y):(usize, usize)| {
+            let x = match edits.get(y.wrapping_sub(first)){
+                Some(&(col, size)) if x > col => {
+                    if removed {cmp::max(x.saturating_sub(size), col)} else {x + size}
+                },
+                _ => x,
+            };
+            if y < editor_rows.num_rows(){
+                (cmp::min(x, editor_rows.get_row(y).len()), y)
+            }else{
+                (x, y)
+            }
+        };
+        let (cursor_x, cursor_y) = shift((self.cursor.cursor_x, self.cursor.cursor_y));
+        self.cursor.cursor_x = cursor_x;
+        self.cursor.cursor_y = cursor_y;
+        self.cursor.anchor = self.cursor.anchor.map(shift);
+    }
+
+    fn process_mouse(&mut self, mouse:MouseEvent){
+        self.completion = None;
+        let screen_row = mouse.row as usize;
+        match mouse.kind{
+            MouseEventKind::Down(MouseButton::Left) if screen_row < self.win_size.1 => {
+                let position = self.file_position(mouse.column, screen_row);
+                let double_click = matches!(
+                    self.last_click,
+                    Some((time, last)) if last == position && time.elapsed() < DOUBLE_CLICK_TIME
+                );
+                self.cursor.cursor_x = position.0;
+                self.cursor.cursor_y = position.1;
+                if double_click{
+                    self.select_word();
+                    self.last_click = None;
+                }else{
+                    self.cursor.anchor = Some(position);
+                    self.last_click = Some((Instant::now(), position));
+                }
+            },
+            MouseEventKind::Drag(MouseButton::Left) => {
+                let position = self.file_position(mouse.column, cmp::min(screen_row, self.win_size.1 - 1));
+                if self.cursor.anchor.is_none(){
+                    self.cursor.anchor = Some((self.cursor.cursor_x, self.cursor.cursor_y));
+                }
+                self.cursor.cursor_x = position.0;
+                self.cursor.cursor_y = position.1;
+            },
+            MouseEventKind::ScrollUp => self.cursor.scroll_view(false, self.editor_rows.num_rows()),
+            MouseEventKind::ScrollDown => self.cursor.scroll_view(true, self.editor_rows.num_rows()),
+            _ => {}
+        }
+    }
+
+    /// Maps a cell in the text area to a `(cursor_x, cursor_y)` position in the file.
+    fn file_position(&self, column:u16, screen_row:usize) -> (usize, usize){
+        let num_rows = self.editor_rows.num_rows();
+        if num_rows == 0{
+            return (0, 0);
+        }
+        let file_row = cmp::min(screen_row + self.cursor.row_offset, num_rows - 1);
+        let render_x = (column as usize).saturating_sub(self.gutter_width()) + self.cursor.col_offset;
+        (self.editor_rows.get_editor_row(file_row).rx_to_cx(render_x), file_row)
+    }
+
+    fn select_word(&mut self){
+        if self.cursor.cursor_y >= self.editor_rows.num_rows(){
+            return;
+        }
+        let row = self.editor_rows.get_row(self.cursor.cursor_y);
+        let at = self.cursor.cursor_x;
+        let is_word = |c:char| self.config.char_class(c) == CharClass::Word;
+
+        let start = row[..at]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| is_word(*c))
+            .last()
+            .map_or(at, |(i, _)| i);
+        let end = row[at..]
+            .char_indices()
+            .find(|(_, c)| !is_word(*c))
+            .map_or(row.len(), |(i, _)| at + i);
+
+        if start != end{
+            self.cursor.anchor = Some((start, self.cursor.cursor_y));
+            self.cursor.cursor_x = end;
+        }
+    }
+
+    /// Render columns of `file_row` covered by the selection, if any.
+    fn selected_render_range(&self, file_row:usize) -> Option<(usize, usize)>{
+        let ((start_x, start_y), (end_x, end_y)) = self.cursor.selection()?;
+        if file_row < start_y || file_row > end_y{
+            return None;
+        }
+        let row = self.editor_rows.get_editor_row(file_row);
+        let from = if file_row == start_y {row.cx_to_rx(start_x)} else {0};
+        let to = if file_row == end_y {row.cx_to_rx(end_x)} else {row.cx_to_rx(row.row_contents.len())};
+        Some((from, to))
+    }
+
+    /// The render columns of row `file_row` that diagnostics cover. A range
+    /// reported as empty still marks one column.
+    fn diagnostic_render_ranges(&self, file_row:usize) -> Vec<(usize, usize, diagnostics::Severity)>{
+        let row = self.editor_rows.get_editor_row(file_row);
+        let len = row.row_contents.len();
+        self.editor_rows.diagnostics
+            .on_row(file_row)
+            .filter_map(|diagnostic| {
+                let (from, to) = diagnostic.columns_on(file_row, len)?;
+                let (from, to) = (row.cx_to_rx(from), row.cx_to_rx(to));
+                Some((from, cmp::max(to, from + 1), diagnostic.severity))
+            })
+            .collect()
+    }
+
+    fn clear_screen() -> crossterm::Result<()>{
+        execute!(stdout(), terminal::Clear(ClearType::All))?;
+        execute!(stdout() , cursor::MoveTo(0,0))
+    }
+
+    fn draw_status_bar(&mut self, frame:&mut Vec<String>) {
+        let mut line = style::Attribute::Reverse.to_string();
+        let info = format!(
+            "{}{}{}{} -- {} lines",
+            self.editor_rows
+                .filename
+                .as_ref()
+                .and_then(|path| path.file_name())
+                .and_then(|name| name.to_str())
+                .unwrap_or(if self.editor_rows.scratch {"[Output]"} else {"[No Name]"}),
+            if self.is_read_only(){" [RO]"} else{""}, if self.dirty > 0 && !self.editor_rows.scratch{" (modified)"} else{""},
+            if self.autosave_active(){" [autosave]"} else{""},
+            self.editor_rows.num_rows()
+        );
+        let info_len = cmp::min(info.len(), self.win_size.0);
+        let line_info = match &self.hex{
+            Some(hex) => format!("hex | {:#x}/{:#x}", hex.cursor, hex.bytes.len()),
+            None => format!(
+                "{} | {}{} | {}/{}",
+                self.editor_rows.file_type().map_or("no ft", |file_type| file_type.name),
+                self.editor_rows.encoding.name(),
+                if self.editor_rows.bom {" BOM"} else {""},
+                self.cursor.cursor_y + 1,
+                self.editor_rows.num_rows()
+            ),
+        };
+        line.push_str(&info[..info_len]);
+        for i in info_len..self.win_size.0 {
+            if self.win_size.0 - i == line_info.len() {
+                line.push_str(&line_info);
+                break;
+            } else {
+                line.push(' ')
+            }
+        }
+        line.push_str(&style::Attribute::Reset.to_string());
+        frame.push(line);
+    }
+
+    fn draw_message_bar(&mut self, frame:&mut Vec<String>) {
+        let line = match self.status_message.message(){
+            Some(msg) => msg.chars().take(self.win_size.0).collect(),
+            None => match self.editor_rows.diagnostics.worst_on(self.cursor.cursor_y){
+                Some(diagnostic) => diagnostic.to_string().chars().take(self.win_size.0).collect(),
+                None => String::new(),
+            },
+        };
+        frame.push(line);
+    }
+
+    /// Width of the sign column, which is only shown while the buffer has diagnostics.
+    fn gutter_width(&self) -> usize{
+        if self.editor_rows.diagnostics.is_empty() {0} else {cmp::min(GUTTER_WIDTH, self.win_size.0 / 2)}
+    }
+
+    fn add_rows(&mut self, frame:&mut Vec<String>) {
+        let screen_rows = self.win_size.1;
+        let gutter = self.gutter_width();
+        let screen_columns = self.win_size.0 - gutter;
+        for i in 0..screen_rows {
+            let mut line = String::new();
+            let file_row = i + self.cursor.row_offset;
+            if file_row >= self.editor_rows.num_rows() {
+                if self.editor_rows.num_rows() == 0 && i == screen_rows / 3 {
+                    let mut welcome = String::from("Rust Editor --- Version 1");
+                    if welcome.len() > screen_columns {
+                        welcome.truncate(screen_columns)
+                    }
+                    let mut padding = (screen_columns - welcome.len()) / 2;
+                    if padding != 0 {
+                        line.push('~');
+                        padding -= 1
+                    }
+                    (0..padding).for_each(|_| line.push(' '));
+                    line.push_str(&welcome);
+                } else {
+                    line.push('~');
+                }
+            } else {
+                if gutter > 0{
+                    match self.editor_rows.diagnostics.worst_on(file_row){
+                        Some(diagnostic) => {
+                            line.push_str(&style::SetForegroundColor(severity_color(diagnostic.severity)).to_string());
+                            line.push(diagnostic.severity.sign());
+                            line.push_str(&style::Attribute::Reset.to_string());
+                            line.push_str(&" ".repeat(gutter - 1));
+                        },
+                        None => line.push_str(&" ".repeat(gutter)),
+                    }
+                }
+                let selected = self.selected_render_range(file_row);
+                let marks = self.diagnostic_render_ranges(file_row);
+                let row = self.editor_rows.get_render(file_row);
+                let col_offset = self.cursor.col_offset;
+
+                // Each character gets the selection's and the worst diagnostic's
+                // attributes; they are only written out where they change.
+                let mut current = (false, None);
+                for (column, ch) in row.chars().enumerate().skip(col_offset).take(screen_columns){
+                    let style = (
+                        selected.is_some_and(|(from, to)| (from..to).contains(&column)),
+                        marks.iter().filter(|(from, to, _)| (*from..*to).contains(&column)).map(|(_, _, severity)| *severity).min()
+                    );
+                    if style != current{
+                        line.push_str(&style::Attribute::Reset.to_string());
+                        if style.0{
+                            line.push_str(&style::Attribute::Reverse.to_string());
+                        }
+                        if let Some(severity) = style.1{
+                            line.push_str(&style::Attribute::Underlined.to_string());
+                            line.push_str(&style::SetForegroundColor(severity_color(severity)).to_string());
+                        }
+                        current = style;
+                    }
+                    line.push(ch);
+                }
+                if current != (false, None){
+                    line.push_str(&style::Attribute::Reset.to_string());
+                }
+            }
+            frame.push(line);
+        }
+    }
+
+    fn refresh_screen(&mut self) ->crossterm::Result<()>{
+        self.draw_frame()?;
+        self.editor_contents.flush()
+    }
+
+    /// Queues what it takes to bring the terminal up to date, without sending it.
+    fn draw_frame(&mut self) -> crossterm::Result<()>{
+        self.follow_hex_cursor();
+        let gutter = self.gutter_width();
+        self.cursor.screen_columns = self.win_size.0 - gutter;
+        self.cursor.scroll(&self.editor_rows);
+        let mut frame = Vec::with_capacity(self.win_size.1 + 2);
+        self.add_rows(&mut frame);
+        self.draw_completion(&mut frame);
+        self.draw_status_bar(&mut frame);
+        self.draw_message_bar(&mut frame);
+
+        queue!(self.editor_contents, cursor::Hide)?;
+        self.screen.draw(&mut self.editor_contents, frame, self.cursor.row_offset, self.win_size.1)?;
+
+        let cursor_x = self.cursor.render_x - self.cursor.col_offset + gutter;
+
+        // The mouse wheel can scroll the cursor out of view; leave it hidden then.
+        if (self.cursor.row_offset..self.cursor.row_offset + self.win_size.1).contains(&self.cursor.cursor_y){
+            let cursor_y = self.cursor.cursor_y - self.cursor.row_offset ;
+
+            queue!(self.editor_contents, cursor::MoveTo(cursor_x  as u16,cursor_y  as u16) ,cursor::Show)?;
+        }
+        Ok(())
+    }
+
+    fn move_cursor(&mut self,direction:KeyCode) {
+        self.cursor.move_cursor(direction , &self.editor_rows);
+    }
+
+    /// Moves the cursor to the top or bottom row on screen.
+    fn move_page(&mut self, up:bool){
+        self.cursor.anchor = None;
+        if up{
+            self.cursor.cursor_y = self.cursor.row_offset
+        }else{
+            self.cursor.cursor_y = cmp::min(
+                self.win_size.1 + self.cursor.row_offset - 1,
+                self.editor_rows.num_rows()
+            )
+        }
+        self.cursor.clamp_x(&self.editor_rows);
+    }
+
+    fn move_word(&mut self, forward:bool) {
+        self.cursor.move_word(forward, &self.editor_rows, &self.config);
+    }
+
+    /// Parses a go-to target: `line`, `line:col`, `+N`/`-N` relative to the
+    /// cursor, or `N%` of the buffer. Lines and columns are 1-based.
+    fn parse_position(&self, input:&str) -> Option<(usize, Option<usize>)>{
+        let input = input.trim();
+        let last_row = self.editor_rows.num_rows().saturating_sub(1);
+        let (line, col) = match input.split_once(':'){
+            Some((line, col)) => (line, Some(col.trim().parse::<usize>().ok()?)),
+            None => (input, None),
+        };
+
+        let row = if let Some(percent) = line.strip_suffix('%'){
+            let percent = cmp::min(percent.trim().parse::<usize>().ok()?, 100);
+            last_row * percent / 100
+        }else if let Some(delta) = line.strip_prefix('+'){
+            self.cursor.cursor_y + delta.trim().parse::<usize>().ok()?
+        }else if let Some(delta) = line.strip_prefix('-'){
+            self.cursor.cursor_y.saturating_sub(delta.trim().parse::<usize>().ok()?)
+        }else{
+            line.trim().parse::<usize>().ok()?.saturating_sub(1)
+        };
+        Some((cmp::min(row, last_row), col))
+    }
+
+    fn goto_position(&mut self, row:usize, col:Option<usize>){
+        let cursor_x = match (col, row < self.editor_rows.num_rows()){
+            (Some(col), true) => {
+                let contents = self.editor_rows.get_row(row);
+                contents
+                    .char_indices()
+                    .nth(col.saturating_sub(1))
+                    .map_or(contents.len(), |(i, _)| i)
+            },
+            _ => 0,
+        };
+        self.cursor.jump_to(cursor_x, row);
+    }
+
+    /// The cursor as a language server position.
+    fn lsp_position(&self) -> lsp::Position{
+        let y = self.cursor.cursor_y;
+        let character = if y < self.editor_rows.num_rows() {lsp::utf16_col(&self.editor_rows.get_row(y), self.cursor.cursor_x)} else {0};
+        lsp::Position{line:y, character}
+    }
+
+    fn goto_lsp_position(&mut self, at:lsp::Position){
+        let num_rows = self.editor_rows.num_rows();
+        let row = cmp::min(at.line, num_rows.saturating_sub(1));
+        let x = if row < num_rows {lsp::byte_col(&self.editor_rows.get_row(row), at.character)} else {0};
+        self.cursor.jump_to(x, row);
+    }
+
+    /// Runs the linter for the file's type over the saved file and marks
+    /// the problems it prints.
+    fn lint(&mut self){
+        let (path, command) = match (&self.editor_rows.filename, self.editor_rows.file_type()){
+            (Some(path), Some(file_type)) if self.hex.is_none() => match self.config.linters.get(file_type.name){
+                Some(command) => (path.clone(), command.clone()),
+                None => return,
+            },
+            _ => return,
+        };
+        match shell::run(&format!("{} {}", command, shell::quote(&path.to_string_lossy())), None){
+            Ok(finished) => {
+                let mut text = finished.stdout;
+                text.push_str(&finished.stderr);
+                self.editor_rows.set_entry_diagnostics(Source::Linter, &quickfix::parse(&text));
+            },
+            Err(err) => self.status_message.set_message(format!("Can't run {}: {}", command, err)),
+        }
+    }
+
+    /// F8/Shift-F8: goes to the next or previous diagnostic in the buffer.
+    fn step_diagnostic(&mut self, forward:bool){
+        let at = (self.cursor.cursor_x, self.cursor.cursor_y);
+        match self.editor_rows.diagnostics.next(at, forward){
+            Some(diagnostic) => {
+                let message = diagnostic.to_string();
+                let (x, y) = diagnostic.start;
+                self.cursor.jump_to(x, y);
+                self.status_message.set_message(message);
+            },
+            None => self.status_message.set_message("No diagnostics in this buffer".into()),
+        }
+    }
+
+    /// Applies a language server's edits to the buffer as one undoable change.
+    fn apply_edits(&mut self, edits:Vec<lsp::TextEdit>){
+        let count = self.editor_rows.num_rows();
+        let old_rows = if count > 0 {self.rows_between(0, count - 1)} else {Vec::new()};
+        let mut rows = old_rows.clone();
+        lsp::apply_edits(&mut rows, edits);
+        if rows == old_rows{
+            return;
+        }
+        let cursor = self.lsp_position();
+        self.record_change(0, count, rows.len());
+        self.editor_rows.replace_rows(0, count, rows);
+        self.goto_lsp_position(cursor);
+        self.dirty += 1;
+    }
+
+    /// The word characters right before the cursor.
+    fn word_before_cursor(&self) -> String{
+        if self.cursor.cursor_y >= self.editor_rows.num_rows(){
+            return String::new();
+        }
+        let row = self.editor_rows.get_row(self.cursor.cursor_y);
+        let mut word:Vec<char> = row[..self.cursor.cursor_x]
+            .chars()
+            .rev()
+            .take_while(|c| self.config.char_class(*c) == CharClass::Word)
+            .collect();
+        word.reverse();
+        word.into_iter().collect()
+    }
+
+    /// Replaces the `prefix_len` bytes before the cursor with `word`.
+    fn complete_word(&mut self, prefix_len:usize, word:&str){
+        if !self.check_writable(){
+            return;
+        }
+        let (x, y) = (self.cursor.cursor_x, self.cursor.cursor_y);
+        let count = if y < self.editor_rows.num_rows() {1} else {0};
+        let row = if count == 1 {self.editor_rows.get_row(y).into_owned()} else {String::new()};
+        let start = x - prefix_len;
+        self.record_change(y, count, 1);
+        self.editor_rows.replace_rows(y, count, vec![format!("{}{}{}", &row[..start], word, &row[x..])]);
+        self.cursor.cursor_x = start + word.len();
+        self.dirty += 1;
+    }
+
+    /// Ctrl-N (`wanted`) or typing: offers the words of the open buffers that
+    /// extend the one before the cursor, nearest and most used first. A
+    /// single one is filled in straight away when asked for.
+    fn show_completion(&mut self, wanted:bool){
+        if self.editor_rows.read_only{
+            self.completion = None;
+            if wanted{
+                self.check_writable();
+            }
+            return;
+        }
+        let prefix = self.word_before_cursor();
+        if prefix.is_empty(){
+            self.completion = None;
+            if wanted{
+                self.status_message.set_message("No word before the cursor to complete".into());
+            }
+            return;
+        }
+
+        let (x, y) = (self.cursor.cursor_x, self.cursor.cursor_y);
+        let start = (x - prefix.len(), y);
+        let narrowed = self.completion.as_mut().is_some_and(|popup| popup.narrow(start, &prefix));
+        if !narrowed{
+            self.completion = Some(Popup::new(start, prefix.clone(), self.collect_words(&prefix)));
+        }
+        let words = &self.completion.as_ref().unwrap().words;
+        match words.len(){
+            0 if wanted => {
+                self.completion = None;
+                self.status_message.set_message("No completions".into());
+            },
+            1 if wanted => {
+                let word = words[0].clone();
+                self.completion = None;
+                self.complete_word(prefix.len(), &word);
+            },
+            _ => {},
+        }
+    }
+
+    /// The words of the open buffers that extend `prefix`, best first.
+    fn collect_words(&self, prefix:&str) -> Vec<String>{
+        let (x, y) = (self.cursor.cursor_x, self.cursor.cursor_y);
+        let config = &self.config;
+        let is_word = |c| config.char_class(c) == CharClass::Word;
+        let mut words = completion::Words::default();
+        let num_rows = self.editor_rows.num_rows();
+        for at in y.saturating_sub(COMPLETION_ROWS)..cmp::min(y + COMPLETION_ROWS + 1, num_rows){
+            let except = if at == y {Some(x)} else {None};
+            words.scan(&self.editor_rows.get_row(at), prefix, at.abs_diff(y), except, is_word);
+        }
+        if let Some(other) = self.stashed.as_ref().filter(|other| other.hex.is_none()){
+            for at in 0..cmp::min(other.editor_rows.num_rows(), 2 * COMPLETION_ROWS){
+                words.scan(&other.editor_rows.get_row(at), prefix, usize::MAX, None, is_word);
+            }
+        }
+        words.ranked()
+    }
+
+    /// Follows typing while completions are shown, hidden or not, and opens
+    /// them once the word is `complete_after` characters long.
+    fn update_completion(&mut self){
+        let long_enough = self.config.complete_after > 0
+            && self.word_before_cursor().chars().count() >= self.config.complete_after;
+        match &self.completion{
+            // Kept only to spare collecting the words while they are typed.
+            Some(popup) if popup.is_hidden() && !long_enough => self.completion = None,
+            Some(_) => self.show_completion(false),
+            None if long_enough => self.show_completion(false),
+            None => {},
+        }
+    }
+
+    /// Puts the chosen word in place of the one being typed.
+    fn accept_completion(&mut self){
+        if let Some(popup) = self.completion.take(){
+            let (x, y) = (self.cursor.cursor_x, self.cursor.cursor_y);
+            if !popup.is_hidden() && y == popup.start.1 && x >= popup.start.0{
+                self.complete_word(x - popup.start.0, popup.selected_word());
+            }
+        }
+    }
+
+    /// Keys for the completion popup while it is open: Up/Down or Ctrl-N/Ctrl-P
+    /// choose, Tab/Enter accept and Esc closes it. Typing goes on to the
+    /// buffer; any other key closes the popup before it is handled, as do
+    /// all but typing while it is hidden.
+    fn process_completion_key(&mut self, key:KeyEvent) -> bool{
+        let popup = match &mut self.completion{
+            Some(popup) => popup,
+            None => return false,
+        };
+        let typing = matches!(
+            (key.code, key.modifiers),
+            (KeyCode::Char(_), KeyModifiers::NONE | KeyModifiers::SHIFT) | (KeyCode::Backspace, KeyModifiers::NONE)
+        );
+        if popup.is_hidden(){
+            if !typing{
+                self.completion = None;
+            }
+            return false;
+        }
+        match key{
+            KeyEvent{
+                code:KeyCode::Up | KeyCode::Down,
+                modifiers:KeyModifiers::NONE,
+                ..
+            } => popup.step(key.code == KeyCode::Down),
+            KeyEvent{
+                code:KeyCode::Char('n' | 'p'),
+                modifiers:KeyModifiers::CONTROL,
+                ..
+            } => popup.step(key.code == KeyCode::Char('n')),
+            KeyEvent{
+                code:KeyCode::Tab | KeyCode::Enter,
+                modifiers:KeyModifiers::NONE,
+                ..
+            } => self.accept_completion(),
+            KeyEvent{
+                code:KeyCode::Esc,
+                ..
+            } => self.completion = None,
+            _ if typing => return false,
+            _ => {
+                self.completion = None;
+                return false;
+            },
+        }
+        true
+    }
+
+    /// Draws the completion popup over the text under the word being
+    /// completed, or above it when there is no room below.
+    fn draw_completion(&self, frame:&mut [String]){
+        let popup = match &self.completion{
+            Some(popup) if !popup.is_hidden() => popup,
+            _ => return,
+        };
+        let (x, y) = popup.start;
+        let rows = self.win_size.1;
+        if y < self.cursor.row_offset || y >= self.cursor.row_offset + rows || y >= self.editor_rows.num_rows(){
+            return;
+        }
+        // The text may have changed under a popup left open.
+        let editor_row = self.editor_rows.get_editor_row(y);
+        if !editor_row.row_contents.is_char_boundary(x){
+            return;
+        }
+        let row = y - self.cursor.row_offset;
+        let shown:Vec<(&str, bool)> = popup.shown().collect();
+        let longest = shown.iter().map(|(word, _)| word.chars().count()).max().unwrap_or(0);
+        let width = cmp::min(longest + 2, self.win_size.0);
+        let render_x = editor_row.cx_to_rx(x);
+        let column = cmp::min(render_x.saturating_sub(self.cursor.col_offset) + self.gutter_width(), self.win_size.0 - width);
+        let top = if row + shown.len() < rows {row + 1} else {row.saturating_sub(shown.len())};
+
+        for (i, (word, selected)) in shown.into_iter().enumerate(){
+            let at = top + i;
+            if at >= rows || at == row{
+                break;
+            }
+            let text:String = format!(" {:<1$} ", word, longest).chars().take(width).collect();
+            let style = if selected {style::Attribute::Bold} else {style::Attribute::Reverse};
+            frame[at] = completion::overlay(&frame[at], column, &text, &style.to_string());
+        }
+    }
+
+    /// Scrolls a whole screen, leaving the cursor on the top row like a pager.
+    fn page(&mut self, down:bool){
+        let rows = self.win_size.1;
+        let top = if down{
+            cmp::min(self.cursor.row_offset + rows, self.editor_rows.num_rows().saturating_sub(1))
+        }else{
+            self.cursor.row_offset.saturating_sub(rows)
+        };
+        self.cursor.jump_to(0, top);
+        self.cursor.row_offset = top;
+    }
+
+    /// Selects the next match of `query` after the cursor, or the previous
+    /// one before it, wrapping around the file.
+    fn find(&mut self, query:&str, forward:bool){
+        let num_rows = self.editor_rows.num_rows();
+        if num_rows == 0{
+            return;
+        }
+        let (x, y) = (self.cursor.cursor_x, cmp::min(self.cursor.cursor_y, num_rows - 1));
+        for step in 0..=num_rows{
+            let at = if forward {(y + step) % num_rows} else {(y + num_rows - step % num_rows) % num_rows};
+            let row = self.editor_rows.get_row(at);
+            let mut matches = row.match_indices(query).map(|(i, _)| i);
+            let found = match (step, forward){
+                (0, true) => matches.find(|&i| i > x),
+                (0, false) => matches.filter(|&i| i < x).last(),
+                (_, true) => matches.next(),
+                (_, false) => matches.last(),
+            };
+            if let Some(i) = found{
+                self.cursor.jump_to(i, at);
+                self.cursor.anchor = Some((i + query.len(), at));
+                return;
+            }
+        }
+        self.status_message.set_message(format!("Pattern not found: {}", query));
+    }
+}
+
+struct CursorController {
+    cursor_x: usize,
+    cursor_y: usize,
+    screen_columns:usize,
+    screen_rows:usize,
+    row_offset:usize,
+    col_offset:usize,
+    render_x: usize,
+    anchor:Option<(usize, usize)>,
+    pinned:Option<(usize, usize)>,
+}
+
+impl CursorController{
+    fn new(win_size:(usize , usize)) -> Self{
+        Self{
+            cursor_x:0,
+            cursor_y:0,
+            screen_columns: win_size.0,
+            screen_rows: win_size.1,
+            row_offset:0,
+            col_offset:0,
+            render_x:0,
+            anchor:None,
+            pinned:None
+        }
+    }
+
+    /// The selected span as ordered `(start, end)` positions, `None` when empty.
+    fn selection(&self) -> Option<((usize, usize), (usize, usize))>{
+        let anchor = self.anchor?;
+        let cursor = (self.cursor_x, self.cursor_y);
+        match (anchor.1, anchor.0).cmp(&(cursor.1, cursor.0)){
+            Ordering::Less => Some((anchor, cursor)),
+            Ordering::Greater => Some((cursor, anchor)),
+            Ordering::Equal => None,
+        }
+    }
+
+    /// Scrolls the viewport without moving the cursor. The view stays put
+    /// until the cursor moves again.
+    fn scroll_view(&mut self, down:bool, num_rows:usize){
+        self.row_offset = if down{
+            cmp::min(self.row_offset + SCROLL_LINES, num_rows.saturating_sub(1))
+        }else{
+            self.row_offset.saturating_sub(SCROLL_LINES)
+        };
+        self.pinned = Some((self.cursor_x, self.cursor_y));
+    }
+
+    fn scroll(&mut self, editor_rows: &EditorRows){
+        self.render_x = 0;
+        if self.cursor_y < editor_rows.num_rows(){
+            self.render_x = self.get_render_x(&editor_rows.get_editor_row(self.cursor_y))
+        }
+
+        if self.pinned != Some((self.cursor_x, self.cursor_y)){
+            self.pinned = None;
+            self.row_offset = cmp::min(self.row_offset , self.cursor_y);
+
+            if self.cursor_y >= self.row_offset + self.screen_rows{
+                self.row_offset = self.cursor_y - self.screen_rows + 1;
+            }
+        }
+
+        self.col_offset = cmp::min(self.col_offset, self.render_x);
+
+        if self.render_x >= self.col_offset + self.screen_columns{
+            self.col_offset = self.render_x - self.screen_columns + 1;
+        }
+
+    }
+
+    fn move_cursor(&mut self, direction: KeyCode , editor_rows: &EditorRows) {
+        let num_rows = editor_rows.num_rows();
+        self.anchor = None;
         match direction {
             KeyCode::Up => {
                 self.cursor_y = self.cursor_y.saturating_sub(1);
             },
             KeyCode::Left => {
                 if self.cursor_x != 0 {
-                    self.cursor_x -= 1;
+                    let row = editor_rows.get_row(self.cursor_y);
+                    self.cursor_x -= row[..self.cursor_x].chars().next_back().map_or(1, char::len_utf8);
                 }
             },
             KeyCode::Down => {
@@ -291,8 +2000,9 @@ impl CursorController{
             KeyCode::Right => {
                 
                 if self.cursor_y < num_rows{
-                    match self.cursor_x.cmp(&editor_rows.get_row(self.cursor_y).len() ){
-                        Ordering::Less => self.cursor_x += 1,
+                    let row = editor_rows.get_row(self.cursor_y);
+                    match self.cursor_x.cmp(&row.len() ){
+                        Ordering::Less => self.cursor_x += row[self.cursor_x..].chars().next().map_or(1, char::len_utf8),
                         Ordering::Equal => {
                             self.cursor_y += 1;
                             return self.cursor_x = 0;
@@ -312,247 +2022,1739 @@ impl CursorController{
             },
             _ => unimplemented!(),
         }
-        let row_len = if self.cursor_y < num_rows{
-            editor_rows.get_row(self.cursor_y).len()
+        self.clamp_x(editor_rows);
+    }
+
+    /// Keeps `cursor_x` within the cursor's row and on a character boundary
+    /// after a move to another row.
+    fn clamp_x(&mut self, editor_rows:&EditorRows){
+        if self.cursor_y >= editor_rows.num_rows(){
+            self.cursor_x = 0;
+            return;
+        }
+        let row = editor_rows.get_row(self.cursor_y);
+        self.cursor_x = cmp::min(self.cursor_x, row.len());
+        while !row.is_char_boundary(self.cursor_x){
+            self.cursor_x -= 1;
+        }
+    }
+
+    /// Moves the cursor to an arbitrary position and centers its row on screen.
+    fn jump_to(&mut self, cursor_x:usize, cursor_y:usize){
+        self.anchor = None;
+        self.pinned = None;
+        self.cursor_x = cursor_x;
+        self.cursor_y = cursor_y;
+        self.row_offset = cursor_y.saturating_sub(self.screen_rows / 2);
+    }
+
+    /// Jumps to the end of the next word or the start of the previous one,
+    /// wrapping across line boundaries.
+    fn move_word(&mut self, forward:bool, editor_rows:&EditorRows, config:&Config){
+        self.anchor = None;
+        if forward{
+            if self.cursor_y >= editor_rows.num_rows(){
+                return;
+            }
+            let row = editor_rows.get_editor_row(self.cursor_y);
+            if self.cursor_x >= row.row_contents.len(){
+                self.cursor_y += 1;
+                self.cursor_x = 0;
+            }else{
+                self.cursor_x = row.word_end(self.cursor_x, config);
+            }
+        }else if self.cursor_x == 0{
+            if self.cursor_y > 0{
+                self.cursor_y -= 1;
+                self.cursor_x = editor_rows.get_row(self.cursor_y).len();
+            }
+        }else{
+            self.cursor_x = editor_rows.get_editor_row(self.cursor_y).word_start(self.cursor_x, config);
+        }
+    }
+
+    fn get_render_x(&self, row: &Row) -> usize {
+        row.cx_to_rx(self.cursor_x)
+    }
+}
+
+struct EditorContents{
+    content:String
+}
+
+impl EditorContents{
+    fn new() -> Self{
+        Self{
+            content:String::new()
+        }
+    }
+
+    fn push_str(&mut self , st:&str){
+        self.content.push_str(st);
+    }
+}
+
+impl std::io::Write for EditorContents{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match std::str::from_utf8(buf){
+            Ok(s) => {
+                self.content.push_str(s);
+                Ok(s.len())
+            }
+            Err(_) => Err(std::io::ErrorKind::WriteZero.into()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let out = write!(stdout(), "{}" , self.content);
+        stdout().flush()?;
+        self.content.clear();
+        out
+    }
+}
+
+/// What the terminal currently shows, one styled string per screen line.
+/// Refreshing only rewrites the lines that differ from it, and vertical
+/// scrolling shifts the text area inside a scroll region first so the lines
+/// already on screen don't have to be sent again.
+struct Screen{
+    lines:Vec<String>,
+    row_offset:usize
+}
+
+impl Screen{
+    fn new() -> Self{
+        Self{
+            lines:Vec::new(),
+            row_offset:0
+        }
+    }
+
+    fn draw(&mut self, out:&mut EditorContents, frame:Vec<String>, row_offset:usize, text_rows:usize) -> crossterm::Result<()>{
+        if self.lines.len() != frame.len(){
+            queue!(out, terminal::Clear(ClearType::All))?;
+            self.lines = vec![String::new(); frame.len()];
+        }else if row_offset != self.row_offset{
+            self.scroll(out, row_offset as isize - self.row_offset as isize, text_rows)?;
+        }
+
+        for (i, line) in frame.iter().enumerate(){
+            if self.lines[i] != *line{
+                queue!(out, cursor::MoveTo(0, i as u16))?;
+                out.push_str(line);
+                queue!(out, terminal::Clear(ClearType::UntilNewLine))?;
+            }
+        }
+        self.lines = frame;
+        self.row_offset = row_offset;
+        Ok(())
+    }
+
+    /// Moves the first `text_rows` lines up (`delta > 0`) or down, leaving
+    /// blank lines behind to be filled in by `draw`.
+    fn scroll(&mut self, out:&mut EditorContents, delta:isize, text_rows:usize) -> crossterm::Result<()>{
+        let distance = delta.unsigned_abs();
+        if distance >= text_rows{
+            return Ok(());
+        }
+        let text = &mut self.lines[..text_rows];
+        out.push_str(&format!("\x1b[1;{}r", text_rows));
+        if delta > 0{
+            queue!(out, terminal::ScrollUp(distance as u16))?;
+            text.rotate_left(distance);
+            text[text_rows - distance..].iter_mut().for_each(String::clear);
+        }else{
+            queue!(out, terminal::ScrollDown(distance as u16))?;
+            text.rotate_right(distance);
+            text[..distance].iter_mut().for_each(String::clear);
+        }
+        out.push_str("\x1b[r");
+        Ok(())
+    }
+}
+
+struct StatusMessage {
+    message: Option<String>,
+    set_time: Option<Instant>,
+}
+
+impl StatusMessage {
+    fn new(initial_message: String) -> Self {
+        Self {
+            message: Some(initial_message),
+            set_time: Some(Instant::now()),
+        }
+    }
+
+    fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+        self.set_time = Some(Instant::now())
+    }
+
+    fn message(&mut self) -> Option<&String> {
+        self.set_time.and_then(|time| {
+            if time.elapsed() > Duration::from_secs(5) {
+                self.message = None;
+                self.set_time = None;
+                None
+            } else {
+                Some(self.message.as_ref().unwrap())
+            }
+        })
+    }
+}
+
+/// One line of the buffer, borrowed from the rope where possible, without its line ending.
+struct Row<'a>{
+    row_contents:Cow<'a, str>,
+    tab_stop:usize
+}
+
+impl Row<'_>{
+    /// The row as drawn on screen, with tabs expanded to spaces.
+    fn render(&self) -> String{
+        let mut idx:usize = 0;
+        let capacity = self.row_contents.chars().fold(0, |acc, next| acc + if next == '\t' { self.tab_stop } else { 1 });
+        let mut render = String::with_capacity(capacity);
+
+        self.row_contents.chars().for_each(|c: char| {
+            idx += 1;
+            if c == '\t'{
+                render.push(' ');
+                while !idx.is_multiple_of(self.tab_stop){
+                    render.push(' ');
+                    idx += 1;
+                }
+            } else{
+                render.push(c);
+            }
+        });
+        render
+    }
+
+    fn cx_to_rx(&self, cursor_x:usize) -> usize{
+        self.row_contents[..cursor_x]
+            .chars()
+            .fold(0, |render_x, c| {
+                if c == '\t' {
+                    render_x + self.tab_stop - (render_x % self.tab_stop)
+                } else {
+                    render_x + 1
+                }
+            })
+    }
+
+    /// Skips whitespace after `at`, then the run of characters sharing a class.
+    fn word_end(&self, at:usize, config:&Config) -> usize{
+        let mut chars = self.row_contents[at..]
+            .char_indices()
+            .map(|(i, c)| (at + i, config.char_class(c)))
+            .skip_while(|(_, class)| *class == CharClass::Whitespace)
+            .peekable();
+        let class = match chars.peek(){
+            Some((_, class)) => *class,
+            None => return self.row_contents.len(),
+        };
+        chars
+            .find(|(_, other)| *other != class)
+            .map_or(self.row_contents.len(), |(i, _)| i)
+    }
+
+    /// Mirror of `word_end`, scanning backwards from `at`.
+    fn word_start(&self, at:usize, config:&Config) -> usize{
+        let mut chars = self.row_contents[..at]
+            .char_indices()
+            .rev()
+            .map(|(i, c)| (i, config.char_class(c)))
+            .skip_while(|(_, class)| *class == CharClass::Whitespace)
+            .peekable();
+        let class = match chars.peek(){
+            Some((_, class)) => *class,
+            None => return 0,
+        };
+        chars
+            .take_while(|(_, other)| *other == class)
+            .last()
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// Inverse of `cx_to_rx`: a render column inside a tab maps to the tab itself.
+    fn rx_to_cx(&self, render_x:usize) -> usize{
+        let mut cur_rx = 0;
+        for (cursor_x, c) in self.row_contents.char_indices(){
+            cur_rx += if c == '\t' {self.tab_stop - (cur_rx % self.tab_stop)} else {1};
+            if cur_rx > render_x{
+                return cursor_x;
+            }
+        }
+        self.row_contents.len()
+    }
+}
+
+struct FileType{
+    name:&'static str,
+    /// What language servers call it.
+    language_id:&'static str,
+    extensions:&'static [&'static str],
+    filenames:&'static [&'static str],
+    line_comment:Option<&'static str>,
+    block_comment:Option<(&'static str, &'static str)>
+}
+
+const FILE_TYPES:&[FileType] = &[
+    FileType{
+        name:"rust",
+        language_id:"rust",
+        extensions:&["rs"],
+        filenames:&[],
+        line_comment:Some("//"),
+        block_comment:Some(("/*", "*/"))
+    },
+    FileType{
+        name:"python",
+        language_id:"python",
+        extensions:&["py", "pyi"],
+        filenames:&[],
+        line_comment:Some("#"),
+        block_comment:None
+    },
+    FileType{
+        name:"shell",
+        language_id:"shellscript",
+        extensions:&["sh", "bash", "zsh"],
+        filenames:&[".bashrc", ".bash_profile", ".profile", ".zshrc"],
+        line_comment:Some("#"),
+        block_comment:None
+    },
+    FileType{
+        name:"toml",
+        language_id:"toml",
+        extensions:&["toml"],
+        filenames:&["Cargo.lock"],
+        line_comment:Some("#"),
+        block_comment:None
+    },
+    FileType{
+        name:"yaml",
+        language_id:"yaml",
+        extensions:&["yaml", "yml"],
+        filenames:&[],
+        line_comment:Some("#"),
+        block_comment:None
+    },
+    // C89 has no line comments, so stick to block comments.
+    FileType{
+        name:"c",
+        language_id:"c",
+        extensions:&["c", "h"],
+        filenames:&[],
+        line_comment:None,
+        block_comment:Some(("/*", "*/"))
+    },
+];
+
+impl FileType{
+    fn detect(path:&Path) -> Option<&'static FileType>{
+        let name = path.file_name().and_then(|name| name.to_str())?;
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        FILE_TYPES.iter().find(|file_type| {
+            file_type.filenames.contains(&name)
+                || extension.is_some_and(|ext| file_type.extensions.contains(&ext))
+        })
+    }
+}
+
+const TAB_STOP: usize = 8;
+const RENDER_CACHE_LIMIT: usize = 1024;
+/// A severity letter and a space.
+const GUTTER_WIDTH: usize = 2;
+
+/// What the file looked like when it was last loaded or saved. The hash
+/// tells a real change apart from a new timestamp on the same contents.
+#[derive(Clone, Copy)]
+struct DiskStamp{
+    modified:Option<SystemTime>,
+    len:u64,
+    hash:u64
+}
+
+impl DiskStamp{
+    fn read(file:&Path) -> io::Result<Self>{
+        let mut reader = fs::File::open(file)?;
+        let modified = reader.metadata()?.modified().ok();
+        let mut hasher = DefaultHasher::new();
+        let mut chunk = vec![0; LOAD_CHUNK];
+        let mut len = 0;
+        loop{
+            let read = reader.read(&mut chunk)?;
+            if read == 0{
+                break;
+            }
+            hasher.write(&chunk[..read]);
+            len += read as u64;
+        }
+        Ok(Self{modified, len, hash:hasher.finish()})
+    }
+}
+
+/// The buffer text, kept in a rope so that opening and editing large files
+/// does not copy or shift every line. Each row is stored followed by its
+/// line ending, so row `n` is rope line `n` and the rope ends in an empty line.
+///
+/// Rendered rows are only built when drawn and are cached until their row
+/// is edited or the tab width changes.
+struct EditorRows{
+    rope:Rope,
+    filename: Option<PathBuf>,
+    line_ending:&'static str,
+    trailing_newline:bool,
+    tab_stop:usize,
+    render_cache:BTreeMap<usize, String>,
+    read_only:bool,
+    encoding:Encoding,
+    bom:bool,
+    /// Unset for buffers not backed by a whole file.
+    disk:Option<DiskStamp>,
+    /// Set along with `disk`; tells when the file is worth looking at again.
+    watch:Option<Watch>,
+    /// Cleared to keep this buffer out of autosave.
+    autosave:bool,
+    /// Set for the buffer holding command output.
+    scratch:bool,
+    /// Bumped on every edit, for language servers to be sent the new text.
+    revision:u64,
+    /// What language servers, linters and the build report about the text.
+    diagnostics:Diagnostics
+}
+
+/// What `inspect` made of a file; text carries its encoding and whether it has a BOM.
+enum FileCheck{
+    Text(Encoding, bool),
+    Binary,
+    TooLarge(u64, Encoding, bool)
+}
+
+const SNIFF_BYTES: usize = 8 * 1024;
+const LOAD_CHUNK: usize = 1024 * 1024;
+const PREVIEW_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Whether this process may write `file`, as the kernel judges it for the
+/// effective user, so that ACLs, immutable files and read-only mounts
+/// count. Nothing is opened, so the file's times stay as they are.
+#[cfg(unix)]
+fn is_writable(file:&Path) -> bool{
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    match CString::new(file.as_os_str().as_bytes()){
+        Ok(path) => unsafe{libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::W_OK, libc::AT_EACCESS) == 0},
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_writable(file:&Path) -> bool{
+    fs::metadata(file).is_ok_and(|metadata| !metadata.permissions().readonly())
+}
+
+/// Applies a language server's `edits` to a file that is not open. It is
+/// read and written as a buffer would be, in its own encoding and line
+/// endings and through the same safe save.
+fn edit_file(path:&Path, edits:Vec<lsp::TextEdit>) -> io::Result<()>{
+    let (encoding, bom) = match EditorRows::inspect(path, u64::MAX, None)?{
+        FileCheck::Text(encoding, bom) | FileCheck::TooLarge(_, encoding, bom) => (encoding, bom),
+        FileCheck::Binary => return Err(io::Error::new(io::ErrorKind::InvalidData, "not a text file")),
+    };
+    let (mut rows, lossy) = EditorRows::load(path.to_path_buf(), encoding, bom, None, |_| Ok(()))?;
+    if lossy{
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("not valid {}", encoding.name())));
+    }
+    let count = rows.num_rows();
+    let mut lines:Vec<String> = (0..count).map(|at| rows.get_row(at).into_owned()).collect();
+    lsp::apply_edits(&mut lines, edits);
+    rows.replace_rows(0, count, lines);
+    rows.save().map(|_| ())
+}
+
+/// Whether two paths lead to the same file; compared as given if either
+/// does not exist.
+fn same_file(a:&Path, b:&Path) -> bool{
+    match (fs::canonicalize(a), fs::canonicalize(b)){
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl EditorRows{
+    fn new() -> Self{
+        Self{
+            rope:Rope::new(),
+            filename:None,
+            line_ending:"\n",
+            trailing_newline:true,
+            tab_stop:TAB_STOP,
+            render_cache:BTreeMap::new(),
+            read_only:false,
+            encoding:Encoding::Utf8,
+            bom:false,
+            disk:None,
+            watch:None,
+            autosave:true,
+            scratch:false,
+            revision:0,
+            diagnostics:Diagnostics::default()
+        }
+    }
+
+    /// Looks at the start of `file` to guess its encoding, or whether it is
+    /// text at all, and at its size, before committing to loading it. With
+    /// an `encoding` given only its BOM is looked for.
+    fn inspect(file:&Path, size_limit:u64, encoding:Option<Encoding>) -> io::Result<FileCheck>{
+        let mut head = Vec::with_capacity(SNIFF_BYTES);
+        let mut reader = fs::File::open(file)?;
+        let size = reader.metadata()?.len();
+        (&mut reader).take(SNIFF_BYTES as u64).read_to_end(&mut head)?;
+
+        let detected = match encoding{
+            Some(encoding) => Some((encoding, !encoding.bom().is_empty() && head.starts_with(encoding.bom()))),
+            None => Encoding::detect(&head),
+        };
+        match detected{
+            None => Ok(FileCheck::Binary),
+            Some((encoding, bom)) if size > size_limit => Ok(FileCheck::TooLarge(size, encoding, bom)),
+            Some((encoding, bom)) => Ok(FileCheck::Text(encoding, bom)),
+        }
+    }
+
+    /// Reads `file` in chunks, calling `progress` with the bytes read so far
+    /// after each one. With a `limit` only that many bytes are read and the
+    /// last partial line is dropped. Bytes that are invalid in `encoding` are
+    /// replaced with U+FFFD; the returned flag says whether that happened.
+    fn load(
+        file:PathBuf,
+        encoding:Encoding,
+        bom:bool,
+        limit:Option<u64>,
+        mut progress:impl FnMut(u64) -> io::Result<()>
+    ) -> io::Result<(Self, bool)>{
+        // Watching starts first so that nothing written meanwhile is missed.
+        let watch = Watch::new(&fs::canonicalize(&file).unwrap_or_else(|_| file.clone()));
+        let mut reader = fs::File::open(&file)?;
+        let modified = reader.metadata()?.modified().ok();
+        let mut builder = RopeBuilder::new();
+        let mut chunk = vec![0; LOAD_CHUNK];
+        let mut decoder = Decoder::new(encoding);
+        let mut hasher = DefaultHasher::new();
+        let mut text = String::new();
+        let mut read = 0;
+        if bom{
+            let mark = &mut chunk[..encoding.bom().len()];
+            reader.read_exact(mark)?;
+            hasher.write(mark);
+            read = encoding.bom().len() as u64;
+        }
+
+        loop{
+            let want = limit.map_or(LOAD_CHUNK, |limit| cmp::min(LOAD_CHUNK as u64, limit - read) as usize);
+            let len = if want == 0 {0} else {reader.read(&mut chunk[..want])?};
+            if len == 0{
+                break;
+            }
+            read += len as u64;
+            hasher.write(&chunk[..len]);
+            text.clear();
+            decoder.decode(&chunk[..len], &mut text);
+            builder.append(&text);
+            progress(read)?;
+        }
+        text.clear();
+        decoder.finish(&mut text);
+        builder.append(&text);
+
+        let mut rope = builder.finish();
+        let truncated = limit.is_some_and(|limit| read >= limit);
+        if truncated && rope.len_chars() > 0 && rope.char(rope.len_chars() - 1) != '\n'{
+            let last_line = rope.line_to_char(rope.len_lines() - 1);
+            rope.remove(last_line..);
+        }
+        let mut rows = Self::from_rope(rope, Some(file));
+        rows.encoding = encoding;
+        rows.bom = bom;
+        if !truncated{
+            rows.disk = Some(DiskStamp{modified, len:read, hash:hasher.finish()});
+            rows.watch = Some(watch);
+        }
+        Ok((rows, decoder.lossy))
+    }
+
+    /// An unnamed buffer holding everything on `reader`, such as piped stdin.
+    /// Returns whether any of it had to be replaced as in `load`.
+    fn from_reader(mut reader:impl Read) -> io::Result<(Self, bool)>{
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let head = &bytes[..cmp::min(bytes.len(), SNIFF_BYTES)];
+        let (encoding, bom) = Encoding::detect(head).unwrap_or((Encoding::Utf8, false));
+
+        let mut decoder = Decoder::new(encoding);
+        let mut text = String::new();
+        decoder.decode(&bytes[if bom {encoding.bom().len()} else {0}..], &mut text);
+        decoder.finish(&mut text);
+
+        let mut rows = Self::from_rope(Rope::from_str(&text), None);
+        rows.encoding = encoding;
+        rows.bom = bom;
+        Ok((rows, decoder.lossy))
+    }
+
+    fn from_rope(mut rope:Rope, filename:Option<PathBuf>) -> Self{
+        let first_line = rope.line(0);
+        let line_ending = if rope.len_lines() > 1 && first_line.len_chars() > 1
+            && first_line.char(first_line.len_chars() - 2) == '\r'{
+            "\r\n"
         }else{
-            0
+            "\n"
         };
-        self.cursor_x = cmp::min(self.cursor_x, row_len);
+        let trailing_newline = rope.len_chars() == 0 || rope.char(rope.len_chars() - 1) == '\n';
+        if !trailing_newline{
+            rope.insert(rope.len_chars(), line_ending);
+        }
+
+        Self{
+            rope,
+            filename,
+            line_ending,
+            trailing_newline,
+            ..Self::new()
+        }
     }
 
-    fn get_render_x(&self, row: &Row) -> usize {
-        row.row_contents[..self.cursor_x]
-            .chars()
-            .fold(0, |render_x, c| {
-                if c == '\t' {
-                    render_x + TAB_STOP - (render_x % TAB_STOP)
-                } else {
-                    render_x + 1
-                }
+    fn set_tab_stop(&mut self, tab_stop:usize){
+        self.tab_stop = tab_stop;
+        self.render_cache.clear();
+    }
+
+    /// Called after every edit, which rewrote the `count` rows from `at`.
+    /// Forgets the cached render of row `at`, and of every row after it
+    /// when the edit added or removed rows.
+    fn invalidate(&mut self, at:usize, count:usize, rows_shifted:bool){
+        self.revision += 1;
+        self.diagnostics.edited(at, count, self.num_rows());
+        if rows_shifted{
+            self.render_cache.split_off(&at);
+        }else{
+            self.render_cache.remove(&at);
+        }
+    }
+
+    /// Rope char index of byte `x` in row `at`.
+    fn char_index(&self, at:usize, x:usize) -> usize{
+        self.rope.byte_to_char(self.rope.line_to_byte(at) + x)
+    }
+
+    fn insert_row(&mut self, at:usize , contents:String) {
+        let idx = self.rope.line_to_char(at);
+        self.rope.insert(idx, &(contents + self.line_ending));
+        self.invalidate(at, 0, true);
+    }
+
+    fn insert_char(&mut self, at:usize, x:usize, ch:char){
+        let idx = self.char_index(at, x);
+        self.rope.insert_char(idx, ch);
+        self.invalidate(at, 1, false);
+    }
+
+    fn delete_char(&mut self, at:usize, x:usize){
+        let idx = self.char_index(at, x);
+        self.rope.remove(idx..idx + 1);
+        self.invalidate(at, 1, false);
+    }
+
+    /// Breaks row `at` in two at byte `x`.
+    fn split_row(&mut self, at:usize, x:usize){
+        let idx = self.char_index(at, x);
+        self.rope.insert(idx, self.line_ending);
+        self.invalidate(at, 1, true);
+    }
+
+    /// Removes the text between two `(x, y)` positions, joining their rows.
+    fn delete_range(&mut self, start:(usize, usize), end:(usize, usize)){
+        let from = self.char_index(start.1, start.0);
+        let to = self.char_index(end.1, end.0);
+        self.rope.remove(from..to);
+        self.invalidate(start.1, end.1 - start.1 + 1, start.1 != end.1);
+    }
+
+    /// Swaps rows `at..at + count` for `rows`, returning the old contents.
+    fn replace_rows(&mut self, at:usize, count:usize, rows:Vec<String>) -> Vec<String>{
+        let old_rows = (at..at + count).map(|i| self.get_row(i).into_owned()).collect();
+        let idx = self.rope.line_to_char(at);
+        self.rope.remove(idx..self.rope.line_to_char(at + count));
+
+        let mut contents = String::new();
+        for row in rows{
+            contents.push_str(&row);
+            contents.push_str(self.line_ending);
+        }
+        self.rope.insert(idx, &contents);
+        self.invalidate(at, count, true);
+        old_rows
+    }
+
+    fn get_render(&mut self , at:usize) -> &str{
+        if self.render_cache.len() >= RENDER_CACHE_LIMIT{
+            self.render_cache.clear();
+        }
+        let (rope, tab_stop) = (&self.rope, self.tab_stop);
+        self.render_cache.entry(at).or_insert_with(|| {
+            Row{
+                row_contents:Self::line_contents(rope, at),
+                tab_stop
+            }.render()
+        })
+    }
+
+    fn get_editor_row(&self , at:usize) -> Row<'_>{
+        Row{
+            row_contents:self.get_row(at),
+            tab_stop:self.tab_stop
+        }
+    }
+
+    fn num_rows(&self) -> usize{
+        self.rope.len_lines() - 1
+    }
+
+    /// Takes on the diagnostics a language server published, whose
+    /// positions are in UTF-16 columns.
+    fn set_server_diagnostics(&mut self, published:&[lsp::Diagnostic]){
+        let num_rows = self.num_rows();
+        let position = |at:lsp::Position| {
+            let row = cmp::min(at.line, num_rows.saturating_sub(1));
+            let x = if row < num_rows {lsp::byte_col(&self.get_row(row), at.character)} else {0};
+            (x, row)
+        };
+        let list = published
+            .iter()
+            .map(|diagnostic| Diagnostic{
+                start:position(diagnostic.start),
+                end:position(diagnostic.end),
+                severity:diagnostic.severity,
+                message:diagnostic.message.clone(),
+                source:Source::Server
             })
+            .collect();
+        self.diagnostics.set(Source::Server, list, num_rows);
+    }
+
+    /// Takes on the build or linter `entries` that are about this file.
+    fn set_entry_diagnostics(&mut self, source:Source, entries:&[quickfix::Entry]){
+        let num_rows = self.num_rows();
+        let list = match &self.filename{
+            Some(filename) => entries
+                .iter()
+                .filter(|entry| entry.line <= num_rows && same_file(&entry.file, filename))
+                .map(|entry| Diagnostic::from_entry(entry, &self.get_row(entry.line - 1), source))
+                .collect(),
+            None => Vec::new(),
+        };
+        self.diagnostics.set(source, list, num_rows);
+    }
+
+    fn file_type(&self) -> Option<&'static FileType>{
+        self.filename.as_deref().and_then(FileType::detect)
+    }
+
+    fn get_row(&self , at:usize) -> Cow<'_, str>{
+        Self::line_contents(&self.rope, at)
+    }
+
+    fn line_contents(rope:&Rope, at:usize) -> Cow<'_, str>{
+        let line = rope.line(at);
+        let mut len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n'{
+            len -= 1;
+            if len > 0 && line.char(len - 1) == '\r'{
+                len -= 1;
+            }
+        }
+        line.slice(..len).into()
+    }
+
+    fn save(&mut self) -> io::Result<usize>{
+        match &self.filename{
+            None => Err(io::Error::other("no file name specified")),
+            Some(name) => {
+                self.check_encodable()?;
+                // Through a symlink to the file it points at.
+                let target = fs::canonicalize(name).unwrap_or_else(|_| name.clone());
+                let (len, hash) = save::write_file(&target, |file| self.write_to(file))?;
+                let modified = fs::metadata(&target)?.modified().ok();
+                self.disk = Some(DiskStamp{modified, len:len as u64, hash});
+                self.watch = Some(Watch::new(&target));
+                Ok(len)
+            }
+        }
+    }
+
+    /// Whether the file no longer matches what was loaded or last saved. A
+    /// new timestamp over the same contents just refreshes the stamp.
+    fn changed_on_disk(&mut self) -> bool{
+        let (name, stamp) = match (&self.filename, self.disk){
+            (Some(name), Some(stamp)) => (name, stamp),
+            _ => return false,
+        };
+        if !self.watch.as_mut().is_none_or(Watch::changed){
+            return false;
+        }
+        let metadata = match fs::metadata(name){
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        if metadata.modified().ok() == stamp.modified && metadata.len() == stamp.len{
+            return false;
+        }
+        match DiskStamp::read(name){
+            Ok(current) if current.len == stamp.len && current.hash == stamp.hash => {
+                self.disk = Some(current);
+                false
+            },
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Accepts the file as it is now on disk as the version the buffer
+    /// replaces, so that the change is not reported again.
+    fn acknowledge_disk(&mut self){
+        if let Some(name) = &self.filename{
+            self.disk = DiskStamp::read(name).ok();
+        }
+    }
+
+    /// Refuses up front a buffer holding characters its encoding cannot
+    /// represent, before anything is truncated.
+    fn check_encodable(&self) -> io::Result<()>{
+        match self.rope.chunks().find_map(|chunk| self.encoding.unencodable(chunk)){
+            Some(ch) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("'{}' cannot be written as {}", ch, self.encoding.name())
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the buffer out in its encoding and with its original line
+    /// endings, returning the length and hash of what was written.
+    fn write_to(&self, writer:impl Write) -> io::Result<(usize, u64)>{
+        let mut writer = io::BufWriter::new(writer);
+        let mut hasher = DefaultHasher::new();
+        let mut end = self.rope.len_chars();
+        if !self.trailing_newline && end > 0{
+            end -= 1;
+            if end > 0 && self.rope.char(end - 1) == '\r'{
+                end -= 1;
+            }
+        }
+
+        let mut written = 0;
+        if self.bom{
+            writer.write_all(self.encoding.bom())?;
+            hasher.write(self.encoding.bom());
+            written += self.encoding.bom().len();
+        }
+        let mut bytes = Vec::new();
+        for chunk in self.rope.slice(..end).chunks(){
+            bytes.clear();
+            self.encoding.encode(chunk, &mut bytes);
+            writer.write_all(&bytes)?;
+            hasher.write(&bytes);
+            written += bytes.len();
+        }
+        writer.flush()?;
+        Ok((written, hasher.finish()))
+    }
+
+    /// Appends row `at` to the end of the row above it.
+    fn join_adjacent_rows(&mut self , at:usize){
+        let end = self.char_index(at - 1, self.get_row(at - 1).len());
+        let start = self.rope.line_to_char(at);
+        self.rope.remove(end..start);
+        self.invalidate(at - 1, 2, true);
     }
 }
 
-struct EditorContents{
-    content:String
+/// One undoable edit: rows `at..at + new_len` replaced what are now `old_rows`.
+struct Change{
+    at:usize,
+    old_rows:Vec<String>,
+    new_len:usize,
+    cursor:(usize, usize)
 }
 
-impl EditorContents{
+impl Change{
+    /// Puts `old_rows` back and returns the change that redoes this one.
+    fn revert(self, editor_rows:&mut EditorRows, cursor:(usize, usize)) -> Change{
+        let new_len = self.old_rows.len();
+        let old_rows = editor_rows.replace_rows(self.at, self.new_len, self.old_rows);
+        Change{
+            at:self.at,
+            old_rows,
+            new_len,
+            cursor
+        }
+    }
+}
+
+const UNDO_LIMIT:usize = 1000;
+
+struct History{
+    undo:Vec<Change>,
+    redo:Vec<Change>,
+    typing_row:Option<usize>
+}
+
+impl History{
     fn new() -> Self{
         Self{
-            content:String::new()
+            undo:Vec::new(),
+            redo:Vec::new(),
+            typing_row:None
         }
     }
 
-    fn push(&mut self , ch:char){
-        self.content.push(ch);
+    fn record(&mut self, editor_rows:&EditorRows, at:usize, old_len:usize, new_len:usize, cursor:(usize, usize), typing:bool){
+        self.redo.clear();
+        if typing && self.typing_row == Some(at){
+            return;
+        }
+        self.typing_row = if typing {Some(at)} else {None};
+
+        let old_rows = (at..at + old_len).map(|i| editor_rows.get_row(i).to_string()).collect();
+        self.undo.push(Change{at, old_rows, new_len, cursor});
+        if self.undo.len() > UNDO_LIMIT{
+            self.undo.remove(0);
+        }
     }
-    
-    fn push_str(&mut self , st:&str){
-        self.content.push_str(st);
+
+    /// Reverts the last change and returns the cursor position from before it.
+    fn undo(&mut self, editor_rows:&mut EditorRows, cursor:(usize, usize)) -> Option<(usize, usize)>{
+        let change = self.undo.pop()?;
+        let restored = change.cursor;
+        self.typing_row = None;
+        self.redo.push(change.revert(editor_rows, cursor));
+        Some(restored)
+    }
+
+    fn redo(&mut self, editor_rows:&mut EditorRows, cursor:(usize, usize)) -> Option<(usize, usize)>{
+        let change = self.redo.pop()?;
+        let restored = change.cursor;
+        self.typing_row = None;
+        self.undo.push(change.revert(editor_rows, cursor));
+        Some(restored)
     }
 }
 
-impl std::io::Write for EditorContents{
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match std::str::from_utf8(buf){
-            Ok(s) => {
-                self.content.push_str(s);
-                Ok(s.len())
+const QUIT_TIMES:u8 = 3;
+/// How far from the cursor row words are collected for completion, either way.
+const COMPLETION_ROWS:usize = 5000;
+
+const PROGRESS_BYTES:u64 = 8 * 1024 * 1024;
+const SCROLL_LINES:usize = 3;
+const DOUBLE_CLICK_TIME:Duration = Duration::from_millis(400);
+
+struct Editor{
+    reader:Reader,
+    output: Output,
+    quit_times:u8,
+    /// Running in a pipeline, where quitting writes the buffer to stdout.
+    filter:bool,
+    last_input:Instant,
+    /// Errors found by the last build, or references to a symbol, and the
+    /// one last jumped to.
+    errors:Vec<quickfix::Entry>,
+    error_at:Option<usize>,
+    /// Whether the scratch buffer holds the list of `errors`.
+    error_list:bool,
+    /// What the last build found, marked in every file it is about.
+    build_errors:Vec<quickfix::Entry>,
+    /// Running language servers by file type name, and the ones that could
+    /// not be started or have stopped, which are not tried again.
+    servers:BTreeMap<&'static str, lsp::Server>,
+    failed_servers:Vec<&'static str>
+}
+
+impl Editor{
+    fn new(config:Config) -> Self{
+        Self { 
+            reader: Reader, 
+            output: Output::new(config),
+            quit_times: QUIT_TIMES,
+            filter: false,
+            last_input: Instant::now(),
+            errors: Vec::new(),
+            error_at: None,
+            error_list: false,
+            build_errors: Vec::new(),
+            servers: BTreeMap::new(),
+            failed_servers: Vec::new()
+        }
+    }
+
+    /// Opens `file`, first asking how to proceed if it looks binary or is
+    /// too large to edit comfortably. Returns `false` if the user backs out.
+    /// Without an `encoding` it is guessed from the file.
+    fn open(&mut self, file:PathBuf, encoding:Option<Encoding>) -> crossterm::Result<bool>{
+        if !file.exists(){
+            self.output.editor_rows.filename = Some(file);
+            return Ok(true);
+        }
+        let size_limit = self.output.config.large_file_mb * 1024 * 1024;
+        let name = file.display().to_string();
+        let (choice, encoding, bom) = match EditorRows::inspect(&file, size_limit, encoding)?{
+            FileCheck::Text(encoding, bom) => (Some('e'), encoding, bom),
+            FileCheck::Binary => (self.output.choose(
+                format!("{} looks binary: [h]ex view, [r]ead-only text (ESC to quit)", name),
+                "hr"
+            )?, Encoding::Utf8, false),
+            FileCheck::TooLarge(size, encoding, bom) => (self.output.choose(
+                format!(
+                    "{} is {} MiB: [r]ead-only, [p]review first {} MiB, [e]dit anyway (ESC to quit)",
+                    name, size / (1024 * 1024), PREVIEW_BYTES / (1024 * 1024)
+                ),
+                "rpe"
+            )?, encoding, bom),
+        };
+        let choice = match choice{
+            Some(choice) => choice,
+            None => return Ok(false),
+        };
+
+        if choice == 'h'{
+            if let Err(err) = self.output.open_hex(file){
+                self.output.status_message.set_message(format!("Can't open {} in hex: {}", name, err));
             }
-            Err(_) => Err(std::io::ErrorKind::WriteZero.into()),
+            return Ok(true);
+        }
+
+        let total = fs::metadata(&file)?.len();
+        let limit = if choice == 'p' {Some(PREVIEW_BYTES)} else {None};
+        let writable = is_writable(&file);
+        let output = &mut self.output;
+        let (mut rows, lossy) = EditorRows::load(file, encoding, bom, limit, |read| {
+            if total < PROGRESS_BYTES{
+                return Ok(());
+            }
+            output.status_message.set_message(format!("Loading {}... {}%", name, read * 100 / total));
+            output.refresh_screen()
+        })?;
+        rows.read_only = choice != 'e' || lossy || !writable || self.output.config.read_only;
+        rows.set_entry_diagnostics(Source::Build, &self.build_errors);
+        self.output.replace_buffer(rows);
+
+        if !writable && !self.output.config.read_only{
+            self.output.status_message.set_message(format!("{} is not writable; opened read-only", name));
+        }else if lossy{
+            self.output.status_message.set_message(format!("{} is not valid {}; opened read-only", name, encoding.name()));
+        }else if choice == 'p'{
+            self.output.status_message.set_message(format!("Showing the first {} MiB (read-only)", PREVIEW_BYTES / (1024 * 1024)));
         }
+        Ok(true)
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        let out = write!(stdout(), "{}" , self.content);
-        stdout().flush()?;
-        self.content.clear();
-        out
+    /// Looks for changes other programs made to the file. A clean buffer is
+    /// reloaded quietly, otherwise the user picks what to do. Returns `false`
+    /// if the buffer was replaced or merged, so a pending save should stop.
+    fn check_disk(&mut self) -> crossterm::Result<bool>{
+        if self.output.hex.is_some() || !self.output.editor_rows.changed_on_disk(){
+            return Ok(true);
+        }
+        let name = self.output.editor_rows.filename.as_ref().unwrap().display().to_string();
+        if self.output.dirty == 0{
+            self.reload()?;
+            self.output.status_message.set_message(format!("{} changed on disk; reloaded", name));
+            return Ok(false);
+        }
+
+        let choice = self.output.choose(
+            format!("{} changed on disk: [r]eload, [k]eep mine, [d]iff and merge (ESC = keep mine)", name),
+            "rkd"
+        )?;
+        match choice{
+            Some('r') => self.reload()?,
+            Some('d') => self.output.merge_with_disk()?,
+            _ => {
+                self.output.editor_rows.acknowledge_disk();
+                return Ok(true);
+            },
+        }
+        Ok(false)
     }
-}
 
-struct StatusMessage {
-    message: Option<String>,
-    set_time: Option<Instant>,
-}
+    /// Saves a modified buffer once input has been idle for the configured
+    /// time, or `at_once` when the terminal lost the focus. A failure is
+    /// reported and retried after another idle period.
+    fn autosave(&mut self, at_once:bool){
+        let delay = Duration::from_secs(self.output.config.autosave);
+        if !self.output.autosave_active() || self.output.dirty == 0 || (!at_once && self.last_input.elapsed() < delay){
+            return;
+        }
+        match self.output.editor_rows.save(){
+            Ok(_) => self.output.dirty = 0,
+            Err(err) => {
+                self.output.status_message.set_message(format!("Autosave failed: {}", err));
+                self.last_input = Instant::now();
+            },
+        }
+    }
 
-impl StatusMessage {
-    fn new(initial_message: String) -> Self {
-        Self {
-            message: Some(initial_message),
-            set_time: Some(Instant::now()),
+    /// Reads the file again in the same encoding, staying on the same row.
+    fn reload(&mut self) -> crossterm::Result<()>{
+        let file = match &self.output.editor_rows.filename{
+            Some(file) => file.clone(),
+            None => return Ok(()),
+        };
+        let (row, encoding) = (self.output.cursor.cursor_y, self.output.editor_rows.encoding);
+        let row_offset = self.output.cursor.row_offset;
+        if self.open(file, Some(encoding))?{
+            self.output.cursor.cursor_y = cmp::min(row, self.output.editor_rows.num_rows());
+            self.output.cursor.row_offset = row_offset;
         }
+        Ok(())
     }
 
-    fn set_message(&mut self, message: String) {
-        self.message = Some(message);
-        self.set_time = Some(Instant::now())
+    /// Loads piped stdin into an unnamed buffer; keys are then read from the terminal.
+    fn open_stdin(&mut self) -> crossterm::Result<()>{
+        if io::stdin().is_terminal(){
+            self.output.status_message.set_message("Nothing was piped to stdin".into());
+            return Ok(());
+        }
+        let (mut rows, lossy) = EditorRows::from_reader(io::stdin().lock())?;
+        rows.read_only = lossy || self.output.config.read_only;
+        self.output.replace_buffer(rows);
+        if lossy{
+            self.output.status_message.set_message("Input is not valid text; opened read-only".into());
+        }
+        Ok(())
     }
 
-    fn message(&mut self) -> Option<&String> {
-        self.set_time.and_then(|time| {
-            if time.elapsed() > Duration::from_secs(5) {
-                self.message = None;
-                self.set_time = None;
-                None
-            } else {
-                Some(self.message.as_ref().unwrap())
+    /// Alt-!: runs a shell command and shows what it printed in the scratch buffer.
+    fn run_command(&mut self) -> crossterm::Result<()>{
+        let command = match prompt!(&mut self.output , "Command: {} (ESC to cancel)"){
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        self.output.status_message.set_message(format!("Running {}...", command));
+        self.output.refresh_screen()?;
+        let finished = match shell::run(&command, None){
+            Ok(finished) => finished,
+            Err(err) => {
+                self.output.status_message.set_message(format!("Can't run {}: {}", command, err));
+                return Ok(());
+            },
+        };
+
+        let mut text = finished.stdout.clone();
+        text.push_str(&finished.stderr);
+        self.output.show_scratch(EditorRows::from_rope(Rope::from_str(&text), None));
+        self.error_list = false;
+        self.output.status_message.set_message(format!("{} {} | Alt-B = Back to file", command, finished.describe()));
+        Ok(())
+    }
+
+    /// Alt-M: runs the configured build command and lists the errors and
+    /// warnings it reports in the scratch buffer, or shows its output if
+    /// there are none.
+    fn make(&mut self) -> crossterm::Result<()>{
+        let command = self.output.config.make.clone();
+        self.output.status_message.set_message(format!("Running {}...", command));
+        self.output.refresh_screen()?;
+        let finished = match shell::run(&command, None){
+            Ok(finished) => finished,
+            Err(err) => {
+                self.output.status_message.set_message(format!("Can't run {}: {}", command, err));
+                return Ok(());
+            },
+        };
+
+        let mut text = finished.stdout.clone();
+        text.push_str(&finished.stderr);
+        self.errors = quickfix::parse(&text);
+        self.build_errors = self.errors.clone();
+        if let Some(rows) = self.output.file_rows(){
+            rows.set_entry_diagnostics(Source::Build, &self.build_errors);
+        }
+        if self.errors.is_empty(){
+            self.error_list = false;
+            self.output.show_scratch(EditorRows::from_rope(Rope::from_str(&text), None));
+            self.output.status_message.set_message(format!("{} {}; no errors found", command, finished.describe()));
+            return Ok(());
+        }
+        self.show_list("problems");
+        Ok(())
+    }
+
+    /// Shows `errors` in the scratch buffer, to go through with Enter and Alt-N/Alt-P.
+    fn show_list(&mut self, what:&str){
+        let list:String = self.errors.iter().map(|entry| format!("{}\n", entry)).collect();
+        let mut rows = EditorRows::from_rope(Rope::from_str(&list), None);
+        rows.read_only = true;
+        self.error_at = None;
+        self.error_list = true;
+        self.output.show_scratch(rows);
+        self.output.status_message.set_message(format!(
+            "{} {} | Enter = Go to | Alt-N/Alt-P = Next/Previous | Alt-B = Back to file", self.errors.len(), what
+        ));
+    }
+
+    /// Alt-N/Alt-P: goes to the next or previous entry of the list.
+    fn step_error(&mut self, forward:bool){
+        let last = match self.errors.len(){
+            0 => {
+                self.output.status_message.set_message("Nothing to go to; Alt-M runs the build".into());
+                return;
+            },
+            len => len - 1,
+        };
+        let index = match (self.error_at, forward){
+            (None, _) => 0,
+            (Some(at), true) => cmp::min(at + 1, last),
+            (Some(at), false) => at.saturating_sub(1),
+        };
+        if let Err(err) = self.goto_error(index){
+            self.output.status_message.set_message(format!("Can't open {}: {}", self.errors[index].file.display(), err));
+        }
+    }
+
+    /// Shows the file an entry is in with the cursor on the reported position.
+    fn goto_error(&mut self, index:usize) -> crossterm::Result<()>{
+        let entry = self.errors[index].clone();
+        self.error_at = Some(index);
+        if !self.open_location(&entry.file)?{
+            return Ok(());
+        }
+        let row = cmp::min(entry.line - 1, self.output.editor_rows.num_rows().saturating_sub(1));
+        self.output.goto_position(row, entry.col);
+        self.output.status_message.set_message(format!("({}/{}) {}", index + 1, self.errors.len(), entry.message));
+        Ok(())
+    }
+
+    /// Puts `file` on screen, opening it unless it is the one being edited.
+    /// Returns `false`, with the reason shown, if that is not possible.
+    fn open_location(&mut self, file:&Path) -> crossterm::Result<bool>{
+        self.output.show_file();
+        let current = self.output.editor_rows.filename.as_ref().and_then(|file| fs::canonicalize(file).ok());
+        if self.output.hex.is_none() && current.is_some() && current == fs::canonicalize(file).ok(){
+            return Ok(true);
+        }
+        if !file.is_file(){
+            self.output.status_message.set_message(format!("{} not found", file.display()));
+            return Ok(false);
+        }
+        if self.output.dirty > 0{
+            self.output.status_message.set_message(format!("Save changes before going to {}", file.display()));
+            return Ok(false);
+        }
+        self.open(file.to_path_buf(), None)
+    }
+
+    /// The language server for the file on screen, started on first use and
+    /// brought up to date with the buffer. With `wanted`, the message bar
+    /// says why there is none.
+    fn language_server(&mut self, wanted:bool) -> Option<&mut lsp::Server>{
+        let output = &mut self.output;
+        let (path, file_type) = match (&output.editor_rows.filename, output.editor_rows.file_type()){
+            (Some(path), Some(file_type)) if !output.editor_rows.scratch && output.hex.is_none() => (path.clone(), file_type),
+            _ => {
+                if wanted{
+                    output.status_message.set_message("No language server for this buffer".into());
+                }
+                return None;
+            },
+        };
+        let command = match output.config.servers.get(file_type.name){
+            Some(command) => command.clone(),
+            None => {
+                if wanted{
+                    output.status_message.set_message(format!("No language server configured; set lsp.{} in ~/.editorrc", file_type.name));
+                }
+                return None;
+            },
+        };
+        if self.failed_servers.contains(&file_type.name){
+            if wanted{
+                output.status_message.set_message(format!("The {} language server is not running", file_type.name));
+            }
+            return None;
+        }
+        if !self.servers.contains_key(file_type.name){
+            match lsp::Server::start(&command, &env::current_dir().unwrap_or_default()){
+                Ok(server) => {
+                    self.servers.insert(file_type.name, server);
+                },
+                Err(err) => {
+                    self.failed_servers.push(file_type.name);
+                    output.status_message.set_message(format!("Can't start {}: {}", command, err));
+                    return None;
+                },
             }
-        })
-    }
-}
-
-#[derive(Default)]
-struct Row{
-    row_contents:String,
-    render:String
-}
+        }
+        // Until it has initialized, which `poll_servers` notices.
+        if !self.servers[file_type.name].ready(){
+            if wanted{
+                output.status_message.set_message(format!("The {} language server is starting", file_type.name));
+            }
+            return None;
+        }
 
-impl Row{
-    fn new(row_contents: String, render: String) -> Self{
-        Self { row_contents, render }
+        let rows = &self.output.editor_rows;
+        let synced = self.servers.get_mut(file_type.name)?.sync(&path, file_type.language_id, rows.revision, || rows.rope.to_string());
+        match synced{
+            Ok(()) => self.servers.get_mut(file_type.name),
+            Err(err) => {
+                self.stop_server(file_type.name, err);
+                None
+            },
+        }
     }
 
-    fn insert_char(&mut self , at:usize , ch:char){
-        self.row_contents.insert(at, ch);
-        EditorRows::render_row(self);
+    fn stop_server(&mut self, name:&'static str, err:io::Error){
+        self.servers.remove(name);
+        self.failed_servers.push(name);
+        if let Some(rows) = self.output.file_rows(){
+            rows.set_server_diagnostics(&[]);
+        }
+        self.output.status_message.set_message(format!("The {} language server stopped: {}", name, err));
     }
 
-    fn delete_char(&mut self , at:usize){
-        self.row_contents.remove(at);
-        EditorRows::render_row(self)
+    /// Picks up diagnostics the language servers sent since the last call.
+    /// A server that just finished initializing is sent the buffer.
+    fn poll_servers(&mut self){
+        let mut rows = self.output.file_rows();
+        let current = rows.as_ref().and_then(|rows| rows.file_type()).map(|file_type| file_type.name);
+        let (mut stopped, mut started) = (Vec::new(), false);
+        for (name, server) in self.servers.iter_mut(){
+            let ready = server.ready();
+            let polled = server.poll();
+            started |= !ready && server.ready();
+            match polled{
+                Ok(true) if current == Some(*name) => {
+                    if let Some(rows) = rows.as_mut(){
+                        rows.set_server_diagnostics(&server.diagnostics);
+                    }
+                },
+                Ok(_) => {},
+                Err(err) => stopped.push((*name, err)),
+            }
+        }
+        for (name, err) in stopped{
+            self.stop_server(name, err);
+        }
+        if started{
+            self.language_server(false);
+        }
     }
-}
-
-const TAB_STOP: usize = 8;
-struct EditorRows{
-    row_contents:Vec<Row>,
-    filename: Option<PathBuf>
-}
-
-impl EditorRows{
-    fn new() -> Self{
-        let mut arg = env::args();
 
-        match arg.nth(1) {
-            None => Self{
-                row_contents:Vec::new(),
-                filename:None
+    /// Asks the language server about the cursor position. Problems are
+    /// reported in the message bar and give `None`.
+    fn ask_server(&mut self, method:&str, params:Value) -> Option<Value>{
+        let at = self.output.lsp_position();
+        match self.language_server(true)?.request_at(method, at, params){
+            Ok(result) => Some(result),
+            Err(err) => {
+                self.output.status_message.set_message(format!("{} failed: {}", method, err));
+                None
             },
-            Some(file) => Self::from_file(file.into())
         }
     }
 
-    fn from_file(file: PathBuf) -> Self{
-        let file_contents = fs::read_to_string(&file).expect("Error in reading File");
-
-        Self{
-            filename:Some(file),
-            row_contents:file_contents.lines().map(|it|{
-                let mut row = Row::new(it.into(), String::new());
-                Self::render_row(&mut row);
-                row
-            }).collect(),
+    /// Alt-H: shows what the language server knows about the symbol under the cursor.
+    fn hover(&mut self){
+        if let Some(result) = self.ask_server("textDocument/hover", json!({})){
+            let text = lsp::hover_text(&result).unwrap_or_else(|| "Nothing known about this".into());
+            self.output.status_message.set_message(text);
         }
     }
 
-    fn insert_row(&mut self, at:usize , contents:String) {
-        let mut new_row = Row::new(contents , String::new());
-        EditorRows::render_row(&mut new_row);
-        self.row_contents.insert(at,new_row);
+    /// Alt-.: goes to where the symbol under the cursor is defined.
+    fn goto_definition(&mut self) -> crossterm::Result<()>{
+        let result = match self.ask_server("textDocument/definition", json!({})){
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        match lsp::locations(&result).first(){
+            Some(location) => {
+                if self.open_location(&location.path)?{
+                    self.output.goto_lsp_position(location.start);
+                }
+            },
+            None => self.output.status_message.set_message("No definition found".into()),
+        }
+        Ok(())
     }
 
-    fn get_render(&self , at:usize) -> &String{
-        &self.row_contents[at].render
-    }
+    /// Alt-R: lists every use of the symbol under the cursor, to go through
+    /// like build errors.
+    fn find_references(&mut self){
+        let result = match self.ask_server("textDocument/references", json!({"context": {"includeDeclaration": true}})){
+            Some(result) => result,
+            None => return,
+        };
+        let locations = lsp::locations(&result);
+        if locations.is_empty(){
+            self.output.status_message.set_message("No references found".into());
+            return;
+        }
 
-    fn get_editor_row(&self , at:usize) -> &Row{
-        &self.row_contents[at]
+        let cwd = env::current_dir().unwrap_or_default();
+        let current = self.output.editor_rows.filename.as_ref().and_then(|file| fs::canonicalize(file).ok());
+        let mut files:BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+        self.errors = locations
+            .into_iter()
+            .map(|location| {
+                let line = location.start.line;
+                let text = if current.as_ref() == Some(&location.path){
+                    (line < self.output.editor_rows.num_rows()).then(|| self.output.editor_rows.get_row(line).into_owned())
+                }else{
+                    let lines = files.entry(location.path.clone()).or_insert_with(|| {
+                        fs::read_to_string(&location.path).unwrap_or_default().lines().map(String::from).collect()
+                    });
+                    lines.get(line).cloned()
+                };
+                let text = text.unwrap_or_default();
+                let col = text[..lsp::byte_col(&text, location.start.character)].chars().count() + 1;
+                quickfix::Entry{
+                    file:location.path.strip_prefix(&cwd).map_or_else(|_| location.path.clone(), Path::to_path_buf),
+                    line:line + 1,
+                    col:Some(col),
+                    message:text.trim().to_string()
+                }
+            })
+            .collect();
+        self.show_list("references");
     }
 
-    fn get_editor_row_mut(&mut self , at:usize) -> &mut Row{
-        &mut self.row_contents[at]
+    /// F2: renames the symbol under the cursor wherever the language server
+    /// finds it. The buffer changes as one undoable edit; other files are
+    /// rewritten on disk.
+    fn rename(&mut self) -> crossterm::Result<()>{
+        if self.language_server(true).is_none() || !self.output.check_writable(){
+            return Ok(());
+        }
+        let name = match prompt!(&mut self.output , "Rename to: {} (ESC to cancel)"){
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let result = match self.ask_server("textDocument/rename", json!({"newName": name})){
+            Some(result) => result,
+            None => return Ok(()),
+        };
+
+        let current = self.output.editor_rows.filename.as_ref().and_then(|file| fs::canonicalize(file).ok());
+        let (mut files, mut failed) = (0, Vec::new());
+        for (path, edits) in lsp::workspace_edits(&result){
+            if current.is_some() && current == fs::canonicalize(&path).ok(){
+                self.output.apply_edits(edits);
+                files += 1;
+            }else{
+                match edit_file(&path, edits){
+                    Ok(()) => files += 1,
+                    Err(err) => failed.push(format!("{}: {}", path.display(), err)),
+                }
+            }
+        }
+        let mut message = format!("Renamed to {} in {} files", name, files);
+        if !failed.is_empty(){
+            message = format!("{}; failed for {}", message, failed.join(", "));
+        }
+        self.output.status_message.set_message(message);
+        Ok(())
     }
 
-    fn num_rows(&self) -> usize{
-        self.row_contents.len()
+    /// Ctrl-Space: completes the word before the cursor from the language
+    /// server's suggestions. Several are offered in the completion popup.
+    fn complete(&mut self){
+        if !self.output.check_writable(){
+            return;
+        }
+        let prefix = self.output.word_before_cursor();
+        let result = match self.ask_server("textDocument/completion", json!({})){
+            Some(result) => result,
+            None => return,
+        };
+        let words:Vec<String> = lsp::completions(&result)
+            .into_iter()
+            .filter(|word| word.len() > prefix.len() && word.starts_with(&prefix))
+            .collect();
+        match words.as_slice(){
+            [] => self.output.status_message.set_message("No completions".into()),
+            [word] => self.output.complete_word(prefix.len(), word),
+            _ => {
+                let start = (self.output.cursor.cursor_x - prefix.len(), self.output.cursor.cursor_y);
+                self.output.completion = Some(Popup::new(start, prefix, words));
+            },
+        }
     }
 
-    fn render_row(row:&mut Row){
-        let mut idx:usize = 0;
-        let capacity = row.row_contents.chars().fold(0, |acc, next| acc + if next == '\t' { TAB_STOP } else { 1 });
+    /// Alt-|: pipes the selected lines, or the whole buffer, through a shell
+    /// command and replaces them with what it prints. If the command fails
+    /// the text is left as it was. Undoable as one change.
+    fn filter_lines(&mut self) -> crossterm::Result<()>{
+        if self.output.hex.is_some(){
+            self.output.status_message.set_message("Filtering is not available in hex mode".into());
+            return Ok(());
+        }
+        if !self.output.check_writable(){
+            return Ok(());
+        }
+        let (first, count) = match self.output.cursor.selection(){
+            Some(_) => match self.output.selected_lines(){
+                Some((first, last)) => (first, last - first + 1),
+                None => return Ok(()),
+            },
+            None => (0, self.output.editor_rows.num_rows()),
+        };
+        let command = match prompt!(&mut self.output , "Filter through: {} (ESC to cancel)"){
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        self.output.status_message.set_message(format!("Running {}...", command));
+        self.output.refresh_screen()?;
 
-        row.render = String::with_capacity(capacity);
+        let mut input = String::new();
+        for at in first..first + count{
+            input.push_str(&self.output.editor_rows.get_row(at));
+            input.push('\n');
+        }
+        let finished = match shell::run(&command, Some(input)){
+            Ok(finished) => finished,
+            Err(err) => {
+                self.output.status_message.set_message(format!("Can't run {}: {}", command, err));
+                return Ok(());
+            },
+        };
+        if !finished.success(){
+            self.output.status_message.set_message(format!("{} {}; text left unchanged", command, finished.describe()));
+            return Ok(());
+        }
 
-        row.row_contents.chars().for_each(|c: char| {
-            idx += 1;
-            if c == '\t'{
-                row.render.push(' ');
-                while idx % TAB_STOP != 0{
-                    row.render.push(' ');
-                    idx += 1;
-                }
-            } else{
-                row.render.push(c);
-            }
-        })
+        let rows:Vec<String> = finished.stdout.lines().map(String::from).collect();
+        let output = &mut self.output;
+        output.record_change(first, count, rows.len());
+        output.editor_rows.replace_rows(first, count, rows);
+        output.cursor.jump_to(0, cmp::min(first, output.editor_rows.num_rows()));
+        output.dirty += 1;
+        let mut message = format!("{} lines filtered through {}", count, command);
+        if let Some(line) = finished.first_error(){
+            message.push_str(&format!(" | stderr: {}", line));
+        }
+        output.status_message.set_message(message);
+        Ok(())
     }
 
-    fn get_row(&self , at:usize) -> &str{
-        &self.row_contents[at].row_contents
+    /// Ctrl-Z: stops the editor under shell job control. The terminal may
+    /// have been resized or drawn over by the time it is resumed, so the
+    /// size is read again and the whole screen redrawn.
+    fn suspend(&mut self) -> crossterm::Result<()>{
+        if !suspend(&self.output.config)?{
+            self.output.status_message.set_message("Suspending is not supported on this platform".into());
+            return Ok(());
+        }
+        let (columns, rows) = terminal::size()?;
+        self.output.resize((columns as usize, rows as usize - 2));
+        Ok(())
     }
 
-    fn save(&self) -> io::Result<usize>{
-        match &self.filename{
-            None => Err(io::Error::new(io::ErrorKind::Other , "no file name specified")),
-            Some(name) => {
-                let mut file = fs::OpenOptions::new().write(true).create(true).open(name)?;
-                let contents: String = self.row_contents
-                    .iter()
-                    .map(|it| it.row_contents.as_str())
-                    .collect::<Vec<&str>>()
-                    .join("\n");
+    /// Writes the buffer out, asking for a file name first if it has none.
+    /// In hex mode the raw bytes are written. Returns whether the file was written.
+    fn save(&mut self) -> crossterm::Result<bool>{
+        let mut format_error = None;
+        let result = match &self.output.hex{
+            Some(hex) if hex.read_only => {
+                self.output.status_message.set_message("Buffer is read-only".into());
+                return Ok(false);
+            },
+            Some(hex) => hex.save(),
+            None => {
+                if !self.output.check_writable(){
+                    return Ok(false);
+                }
+                if self.output.editor_rows.filename.is_none(){
+                    let prompt = prompt!(&mut self.output , "Save as: {} (ESC to cancel)").map(|it| it.into());
+                    if prompt.is_none(){
+                        self.output.status_message.set_message("Save Aborted".into());
+                        return Ok(false)
+                    }
+                    self.output.editor_rows.filename = prompt
+                }
+                if !self.check_disk()?{
+                    return Ok(false);
+                }
+                format_error = self.output.format_buffer();
+                self.output.editor_rows.save()
+            },
+        };
 
-                file.set_len(contents.len() as u64);
-                file.write_all(contents.as_bytes())?;
-                Ok(contents.as_bytes().len())
-            }
+        match result{
+            Ok(len) => {
+                let mut message = format!("{} bytes written to disk" , len);
+                if let Some(error) = format_error{
+                    message = format!("{}; not formatted: {}", message, error);
+                }
+                self.output.status_message.set_message(message);
+                self.output.dirty = 0;
+                self.output.lint();
+                if let Some(server) = self.language_server(false){
+                    let _ = server.saved();
+                }
+                Ok(true)
+            },
+            Err(err) => {
+                self.output.status_message.set_message(format!("Can't save! {}", err));
+                Ok(false)
+            },
         }
     }
 
-    fn join_adjacent_rows(&mut self , at:usize){
-        let cur_row = self.row_contents.remove(at);
-        let prev_row = self.get_editor_row_mut(at - 1);
-        prev_row.row_contents.push_str(&cur_row.row_contents);
-        Self::render_row(prev_row);
+    /// less-style keys for read-only files, where typing does nothing else.
+    /// Scratch lists get none of them, so that `q` does not quit the editor
+    /// from an error list. Returns `false` to quit.
+    fn pager_key(&mut self, key:char) -> crossterm::Result<bool>{
+        let output = &mut self.output;
+        match key{
+            ' ' | 'b' => output.page(key == ' '),
+            'g' => output.cursor.jump_to(0, 0),
+            'G' => output.cursor.jump_to(0, output.editor_rows.num_rows().saturating_sub(1)),
+            '/' => {
+                if let Some(query) = prompt!(*output , "Search: {} (ESC to cancel)"){
+                    output.find(&query, true);
+                    output.last_search = Some(query);
+                }
+            },
+            'n' | 'N' => match output.last_search.clone(){
+                Some(query) => output.find(&query, key == 'n'),
+                None => output.status_message.set_message("No previous search".into()),
+            },
+            // Never quits past unsaved changes, wherever they are.
+            _ if output.has_unsaved_changes() => {
+                output.status_message.set_message("File has unsaved changes; Ctrl-Q to quit anyway".into());
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
     }
-}
 
-const QUIT_TIMES:u8 = 3;
+    /// Flips between hex mode and text for the current file, which must be saved first.
+    fn toggle_hex(&mut self) -> crossterm::Result<()>{
+        let file = match &self.output.editor_rows.filename{
+            Some(file) if file.exists() => file.clone(),
+            _ => {
+                self.output.status_message.set_message("No file to switch views on".into());
+                return Ok(());
+            },
+        };
+        if self.output.dirty > 0{
+            self.output.status_message.set_message("Save changes before switching views".into());
+        }else if self.output.hex.is_some(){
+            self.open(file, None)?;
+        }else if let Err(err) = self.output.open_hex(file.clone()){
+            self.output.status_message.set_message(format!("Can't open {} in hex: {}", file.display(), err));
+        }
+        Ok(())
+    }
 
-struct Editor{
-    reader:Reader,
-    output: Output,
-    quit_times:u8
-}
+    /// Asks for an encoding, then either reopens the file decoded with it or
+    /// saves the buffer converted to it.
+    fn change_encoding(&mut self) -> crossterm::Result<()>{
+        let input = match prompt!(&mut self.output , "Encoding: {} (utf-8[-bom], utf-16[le|be], latin1, cp1252 | ESC to cancel)"){
+            Some(input) => input,
+            None => return Ok(()),
+        };
+        let (encoding, bom) = match Encoding::from_name(&input){
+            Some(found) => found,
+            None => {
+                self.output.status_message.set_message(format!("Unknown encoding: {}", input));
+                return Ok(());
+            },
+        };
+        let choice = self.output.choose(
+            format!("[r]eopen file as {0}, [s]ave as {0} (ESC to cancel)", encoding.name()),
+            "rs"
+        )?;
 
-impl Editor{
-    fn new() -> Self{
-        Self { 
-            reader: Reader, 
-            output: Output::new(),
-            quit_times: QUIT_TIMES
+        match choice{
+            Some('r') => {
+                let file = match &self.output.editor_rows.filename{
+                    Some(file) if file.exists() => file.clone(),
+                    _ => {
+                        self.output.status_message.set_message("No file to reopen".into());
+                        return Ok(());
+                    },
+                };
+                if self.output.dirty > 0 && self.output.choose("Discard unsaved changes? [y]es, [n]o".into(), "yn")? != Some('y'){
+                    return Ok(());
+                }
+                self.open(file, Some(encoding))?;
+            },
+            Some('s') => {
+                if !self.output.check_writable(){
+                    return Ok(());
+                }
+                let rows = &mut self.output.editor_rows;
+                let previous = (rows.encoding, rows.bom);
+                (rows.encoding, rows.bom) = (encoding, bom);
+                if !self.save()?{
+                    let rows = &mut self.output.editor_rows;
+                    (rows.encoding, rows.bom) = previous;
+                }
+            },
+            _ => {},
         }
+        Ok(())
     }
 
     fn process_keypress(&mut self) -> crossterm::Result<bool>{
-        match self.reader.read_key()?{
+        // The file is checked for outside changes and autosaved whenever
+        // input goes quiet, and when the terminal loses or regains the
+        // focus, where it reports that.
+        let event = match self.reader.read_event()?{
+            None => {
+                if self.check_disk()?{
+                    self.autosave(false);
+                }
+                self.language_server(false);
+                return Ok(true);
+            },
+            Some(event @ (Event::FocusGained | Event::FocusLost)) => {
+                if self.check_disk()? && event == Event::FocusLost{
+                    self.autosave(true);
+                }
+                return Ok(true);
+            },
+            Some(event) => event,
+        };
+        self.last_input = Instant::now();
+        let key = match event{
+            Event::Key(key) => key,
+            Event::Mouse(mouse) => {
+                self.output.process_mouse(mouse);
+                return Ok(true);
+            },
+            Event::Resize(columns, rows) => {
+                self.output.resize((columns as usize, (rows as usize).saturating_sub(2)));
+                return Ok(true);
+            },
+            Event::FocusGained | Event::FocusLost | Event::Paste(_) => return Ok(true),
+        };
+        if self.output.process_hex_key(key) || self.output.process_completion_key(key){
+            return Ok(true);
+        }
+        match key{
             KeyEvent{
                 code:KeyCode::Char('q'),
-                modifiers:KeyModifiers::CONTROL
+                modifiers:KeyModifiers::CONTROL,
+                ..
             } => {
-                if self.output.dirty > 0 && self.quit_times > 0{
+                if self.output.has_unsaved_changes() && self.quit_times > 0 && !self.filter{
                     self.output.status_message.set_message(format!(
                         "WARNING! File has unsaved changes. Press CTRL-Q {} more times to quit",
                         self.quit_times
@@ -562,64 +3764,278 @@ impl Editor{
                 }
                 return Ok(false);
             },
+            KeyEvent{
+                code:KeyCode::Char('z'),
+                modifiers:KeyModifiers::CONTROL,
+                ..
+            } => self.suspend()?,
             KeyEvent {
                 code: direction @ (KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
                                             | KeyCode::Home | KeyCode::End),
                 modifiers: KeyModifiers::NONE,
+                ..
             } => self.output.move_cursor(direction),
+            KeyEvent {
+                code: direction @ (KeyCode::Left | KeyCode::Right),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.output.move_word(matches!(direction, KeyCode::Right)),
+            KeyEvent {
+                code: KeyCode::Home,
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.output.cursor.jump_to(0, 0),
+            KeyEvent {
+                code: KeyCode::End,
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => {
+                let num_rows = self.output.editor_rows.num_rows();
+                if num_rows > 0{
+                    let last_row = num_rows - 1;
+                    let end = self.output.editor_rows.get_row(last_row).len();
+                    self.output.cursor.jump_to(end, last_row);
+                }
+            },
+            KeyEvent {
+                code: direction @ (KeyCode::Up | KeyCode::Down),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.output.move_lines(matches!(direction, KeyCode::Up)),
+            KeyEvent {
+                code: KeyCode::Char('d'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.output.duplicate_lines(),
+            KeyEvent {
+                code: KeyCode::Char('k'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.output.delete_lines(),
+            KeyEvent {
+                code: KeyCode::Char('j'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.output.join_lines(),
+            // Alt-Shift-O would send `ESC O`, which the terminal reserves for function keys.
+            KeyEvent {
+                code: code @ KeyCode::Char('o' | 'i'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.output.open_line(code == KeyCode::Char('i')),
+            // Ctrl-/ arrives as Ctrl-7 on most terminals.
+            KeyEvent {
+                code: KeyCode::Char('/'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } | KeyEvent {
+                code: KeyCode::Char('7'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.output.toggle_comment(),
+            KeyEvent {
+                code: code @ KeyCode::Char('u' | 'e'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.output.undo(code == KeyCode::Char('e')),
+            KeyEvent {
+                code: KeyCode::Char('t'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => {
+                if let Some(input) = prompt!(&mut self.output , "Tab width: {} (ESC to cancel)"){
+                    match input.trim().parse::<usize>(){
+                        Ok(width) if width > 0 => self.output.editor_rows.set_tab_stop(width),
+                        _ => self.output.status_message.set_message(format!("Invalid tab width: {}", input)),
+                    }
+                }
+            },
+            KeyEvent {
+                code: KeyCode::Char('g'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => {
+                if let Some(input) = prompt!(&mut self.output , "Go to line: {} (line[:col], +N, -N, N% | ESC to cancel)"){
+                    match self.output.parse_position(&input){
+                        Some((row, col)) => self.output.goto_position(row, col),
+                        None => self.output.status_message.set_message(format!("Invalid position: {}", input)),
+                    }
+                }
+            },
             KeyEvent {
                 code: val @ (KeyCode::PageUp | KeyCode::PageDown),
                 modifiers: KeyModifiers::NONE,
+                ..
+            } => self.output.move_page(matches!(val , KeyCode::PageUp)),
+            KeyEvent {
+                code: KeyCode::Tab,
+                modifiers: KeyModifiers::NONE,
+                ..
             } => {
-                if matches!(val , KeyCode::PageUp){
-                    self.output.cursor.cursor_y = self.output.cursor.row_offset
+                let spans_rows = matches!(self.output.cursor.selection(), Some(((_, start_y), (_, end_y))) if start_y != end_y);
+                if spans_rows{
+                    self.output.indent_lines(false)
                 }else{
-                    self.output.cursor.cursor_y = cmp::min(
-                        self.output.win_size.1 + self.output.cursor.row_offset - 1,
-                        self.output.editor_rows.num_rows()
-                    )
+                    self.output.insert_tab()
                 }
             },
             KeyEvent {
-                code: code @ (KeyCode::Char(..) | KeyCode::Tab),
+                code: KeyCode::BackTab,
+                ..
+            } => self.output.indent_lines(true),
+            KeyEvent {
+                code: KeyCode::Char(key @ (' ' | 'b' | 'g' | 'G' | '/' | 'n' | 'N' | 'q')),
+                modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+                ..
+            } if self.output.is_read_only() && !self.output.editor_rows.scratch => return self.pager_key(key),
+            KeyEvent {
+                code: KeyCode::Char(ch),
                 modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
-            } => self.output.insert_char(match code {
-                KeyCode::Tab => '\t',
-                KeyCode::Char(ch) => ch,
-                _ => unreachable!(),
-            }),
+                ..
+            } => {
+                self.output.insert_char(ch);
+                self.output.update_completion();
+            },
             KeyEvent {
                 code: KeyCode::Char('s'),
                 modifiers: KeyModifiers::CONTROL,
+                ..
             } => {
-                if matches!(self.output.editor_rows.filename , None){
-                    let prompt = prompt!(&mut self.output , "Save as: {} (ESC to cancel)").map(|it| it.into());
-                    if let None = prompt{
-                        self.output.status_message.set_message("Save Aborted".into());
-                        return Ok(true)
-                    }
-                    self.output.editor_rows.filename = prompt
+                self.save()?;
+            },
+            KeyEvent {
+                code: KeyCode::Char('c'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.change_encoding()?,
+            KeyEvent {
+                code: KeyCode::Char('x'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.toggle_hex()?,
+            KeyEvent {
+                code: KeyCode::Char('!'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.run_command()?,
+            KeyEvent {
+                code: KeyCode::Char('|'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.filter_lines()?,
+            KeyEvent {
+                code: KeyCode::Char('b'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.output.switch_buffers(),
+            KeyEvent {
+                code: KeyCode::Char('m'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.make()?,
+            KeyEvent {
+                code: KeyCode::Char('h'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.hover(),
+            KeyEvent {
+                code: KeyCode::Char('.'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.goto_definition()?,
+            KeyEvent {
+                code: KeyCode::Char('r'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.find_references(),
+            KeyEvent {
+                code: KeyCode::F(2),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.rename()?,
+            KeyEvent {
+                code: KeyCode::Char(' '),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.complete(),
+            KeyEvent {
+                code: KeyCode::Char('n'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.output.show_completion(true),
+            KeyEvent {
+                code: code @ KeyCode::Char('n' | 'p'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => self.step_error(code == KeyCode::Char('n')),
+            KeyEvent {
+                code: KeyCode::F(8),
+                modifiers: modifiers @ (KeyModifiers::NONE | KeyModifiers::SHIFT),
+                ..
+            } => self.output.step_diagnostic(modifiers == KeyModifiers::NONE),
+            KeyEvent {
+                code: KeyCode::Enter,
+                modifiers: KeyModifiers::NONE,
+                ..
+            } if self.error_list && self.output.editor_rows.scratch && self.output.cursor.cursor_y < self.errors.len() => {
+                self.goto_error(self.output.cursor.cursor_y)?;
+            },
+            KeyEvent {
+                code: KeyCode::Char('a'),
+                modifiers: KeyModifiers::ALT,
+                ..
+            } => {
+                let output = &mut self.output;
+                if output.config.autosave == 0{
+                    output.status_message.set_message("Autosave is off; set autosave in ~/.editorrc".into());
+                }else{
+                    output.editor_rows.autosave = !output.editor_rows.autosave;
+                    let state = if output.editor_rows.autosave {"on"} else {"off"};
+                    output.status_message.set_message(format!("Autosave {} for this buffer", state));
+                }
+            },
+            KeyEvent {
+                code: KeyCode::Char('f'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } if self.output.hex.is_some() => {
+                if let Some(input) = prompt!(&mut self.output , "Hex search: {} (e.g. de ad be ef or \"text\" | ESC to cancel)"){
+                    self.output.find_hex(&input);
                 }
-
-                self.output.editor_rows.save().map(|len|{
-                    self.output.status_message.set_message(format!("{} bytes written to disk" , len));
-                    self.output.dirty = 0
-                })?;
-                
             },
             KeyEvent {
                 code: KeyCode::Enter,
                 modifiers: KeyModifiers::NONE,
+                ..
             } => self.output.insert_newline(),
             KeyEvent {
                 code: key @ (KeyCode::Backspace | KeyCode::Delete),
                 modifiers: KeyModifiers::NONE,
+                ..
             } => {
-                if matches!(key, KeyCode::Delete) {
+                if matches!(key, KeyCode::Delete) && self.output.cursor.selection().is_none() {
                     self.output.move_cursor(KeyCode::Right)
                 }
-                self.output.delete_char()
+                self.output.delete_char();
+                if self.output.completion.is_some(){
+                    self.output.update_completion();
+                }
             }
+            // Most terminals send Ctrl-H for Ctrl-Backspace.
+            KeyEvent {
+                code: KeyCode::Backspace,
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } | KeyEvent {
+                code: KeyCode::Char('h'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.output.delete_word(false),
+            KeyEvent {
+                code: KeyCode::Delete,
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => self.output.delete_word(true),
             _ =>{}
         }
         self.quit_times = QUIT_TIMES;
@@ -627,6 +4043,7 @@ impl Editor{
     }
 
     fn run(&mut self) -> crossterm::Result<bool> {
+        self.poll_servers();
         self.output.refresh_screen()?;
         self.process_keypress()
     }
@@ -643,16 +4060,16 @@ macro_rules! prompt {
             match Reader.read_key()? {
                 KeyEvent {
                     code:KeyCode::Enter,
-                    modifiers:KeyModifiers::NONE
-                } => {
-                    if !input.is_empty() {
-                        output.status_message.set_message(String::new());
-                        break;
-                    }
+                    modifiers:KeyModifiers::NONE,
+                    ..
+                } if !input.is_empty() => {
+                    output.status_message.set_message(String::new());
+                    break;
                 },
                 KeyEvent {
                     code: KeyCode::Backspace | KeyCode::Delete,
                     modifiers: KeyModifiers::NONE,
+                    ..
                 } =>  {
                     input.pop();
                 },
@@ -667,6 +4084,7 @@ macro_rules! prompt {
                 KeyEvent {
                     code: code @ (KeyCode::Char(..) | KeyCode::Tab),
                     modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+                    ..
                 } => input.push(match code {
                         KeyCode::Tab => '\t',
                         KeyCode::Char(ch) => ch,
@@ -677,4 +4095,187 @@ macro_rules! prompt {
         }
         if input.is_empty() { None } else { Some (input) }
     }};
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Sends the queued output nowhere, returning how many bytes it was.
+    /// With `full`, the screen is repainted as if nothing were on it.
+    fn render(output:&mut Output, full:bool) -> usize{
+        if full{
+            output.screen = Screen::new();
+        }
+        output.draw_frame().unwrap();
+        mem::take(&mut output.editor_contents.content).len()
+    }
+
+    fn sample(full:bool) -> Output{
+        let text:String = (0..200).map(|i| format!("{:>3}: the quick brown fox jumps over the lazy dog\n", i)).collect();
+        let mut output = editor(&text);
+        render(&mut output, full);
+        output
+    }
+
+    fn editor(text:&str) -> Output{
+        let mut output = Output::with_size(Config::default(), (80, 22));
+        output.replace_buffer(EditorRows::from_rope(Rope::from_str(text), None));
+        output
+    }
+
+    #[test]
+    fn up_and_down_stop_on_a_character_boundary(){
+        let mut output = editor("a\u{e9}\nabcd\n\u{e9}\u{e9}\u{e9}\n");
+        output.cursor.jump_to(2, 1);
+        output.move_cursor(KeyCode::Up);
+        assert_eq!((output.cursor.cursor_x, output.cursor.cursor_y), (1, 0));
+        output.cursor.jump_to(3, 1);
+        output.move_cursor(KeyCode::Down);
+        assert_eq!((output.cursor.cursor_x, output.cursor.cursor_y), (2, 2));
+        output.move_cursor(KeyCode::Down);
+        assert_eq!((output.cursor.cursor_x, output.cursor.cursor_y), (0, 3));
+        render(&mut output, false);
+    }
+
+    #[test]
+    fn deleting_a_line_keeps_the_cursor_on_a_character_boundary(){
+        let mut output = editor("abcd\n\u{e9}\u{e9}\n");
+        output.cursor.jump_to(3, 0);
+        output.delete_lines();
+        assert_eq!((output.cursor.cursor_x, output.cursor.cursor_y), (2, 0));
+        render(&mut output, false);
+    }
+
+    #[test]
+    fn page_down_keeps_the_cursor_within_the_row(){
+        let mut output = editor(&format!("a long first row of text\n{}\u{e9}\u{e9}", "short\n".repeat(20)));
+        output.cursor.jump_to(20, 0);
+        output.move_page(false);
+        assert_eq!((output.cursor.cursor_x, output.cursor.cursor_y), (4, 21));
+        output.cursor.jump_to(3, 0);
+        output.move_page(false);
+        assert_eq!((output.cursor.cursor_x, output.cursor.cursor_y), (2, 21));
+        render(&mut output, false);
+    }
+
+    #[test]
+    fn completion_popup_survives_the_text_changing_under_it(){
+        let popup = || Popup::new((3, 1), "ab".into(), vec!["abcd".into()]);
+        let mut output = editor("abcd\nxx ab\n");
+        output.completion = Some(popup());
+        render(&mut output, false);
+        output.replace_buffer(EditorRows::from_rope(Rope::from_str("abcd\n"), None));
+        assert!(output.completion.is_none());
+        output.completion = Some(popup());
+        output.editor_rows.replace_rows(1, 1, vec!["\u{e9}\u{e9}".into()]);
+        render(&mut output, false);
+    }
+
+    /// Toggles the comments on rows `first..=last` of `text` in a file
+    /// called `name`.
+    fn toggle_comment(name:&str, text:&str, first:usize, last:usize) -> Vec<String>{
+        let mut output = Output::with_size(Config::default(), (80, 22));
+        output.replace_buffer(EditorRows::from_rope(Rope::from_str(text), Some(name.into())));
+        output.cursor.anchor = Some((0, first));
+        output.cursor.cursor_y = last + 1;
+        output.toggle_comment();
+        (0..output.editor_rows.num_rows()).map(|at| output.editor_rows.get_row(at).into_owned()).collect()
+    }
+
+    #[test]
+    fn toggles_line_comments(){
+        let text = "fn main(){\n    let x = 1;\n\n    // done\n}";
+        let commented = toggle_comment("main.rs", text, 1, 3);
+        assert_eq!(commented, ["fn main(){", "    // let x = 1;", "", "    // // done", "}"]);
+        assert_eq!(toggle_comment("main.rs", &commented.join("\n"), 1, 3).join("\n"), text);
+        assert_eq!(toggle_comment("main.rs", text, 3, 3)[3], "    done");
+    }
+
+    #[test]
+    fn toggles_block_comments(){
+        let text = "int x;\n  /* int y; */";
+        assert_eq!(toggle_comment("main.c", text, 0, 0), ["/* int x; */", "  /* int y; */"]);
+        assert_eq!(toggle_comment("main.c", text, 1, 1), ["int x;", "  int y;"]);
+        assert_eq!(toggle_comment("main.c", "/* a */ int x; /* b */", 0, 0), ["/* /* a */ int x; /* b */ */"]);
+    }
+
+    #[test]
+    fn deleting_past_either_end_of_a_hex_buffer_changes_nothing(){
+        let mut output = editor("");
+        output.hex = Some(HexBuffer{bytes:vec![1, 2], filename:PathBuf::new(), cursor:2, low_nibble:false, read_only:false});
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        assert!(output.process_hex_key(key(KeyCode::Delete)));
+        output.hex.as_mut().unwrap().move_to(0);
+        assert!(output.process_hex_key(key(KeyCode::Backspace)));
+        assert_eq!(output.dirty, 0);
+        assert!(output.process_hex_key(key(KeyCode::Delete)));
+        assert_eq!(output.dirty, 1);
+        assert_eq!(output.hex.unwrap().bytes, [2]);
+    }
+
+    #[test]
+    fn recovery_keeps_earlier_dumps_and_the_file_encoding(){
+        let dir = env::temp_dir().join(format!("editor-recovery-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("notes.txt");
+        let bytes = b"caf\xe9\r\nna\xefve\r\n";
+        fs::write(&file, bytes).unwrap();
+        let mut output = editor("");
+        output.replace_buffer(EditorRows::load(file, Encoding::Latin1, false, None, |_| Ok(())).unwrap().0);
+        output.dirty = 1;
+        output.write_recovery();
+        output.write_recovery();
+        let first = fs::read(dir.join("notes.txt.recovered"));
+        let second = fs::read(dir.join("notes.txt.1.recovered"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first.unwrap(), bytes);
+        assert_eq!(second.unwrap(), bytes);
+    }
+
+    #[test]
+    fn edit_file_keeps_encoding_and_line_endings(){
+        let dir = env::temp_dir().join(format!("editor-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("rename.c");
+        fs::write(&file, b"int foo; /* caf\xe9 */\r\nfoo = 1;").unwrap();
+        let edit = |line, character| lsp::TextEdit{
+            start:lsp::Position{line, character},
+            end:lsp::Position{line, character:character + 3},
+            new_text:"bar".into()
+        };
+        let result = edit_file(&file, vec![edit(0, 4), edit(1, 0)]);
+        let written = fs::read(&file);
+        fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(written.unwrap(), b"int bar; /* caf\xe9 */\r\nbar = 1;");
+    }
+
+    /// Sends `times` steps to a fresh editor, full repaint first, and
+    /// prints the bytes each way of redrawing takes.
+    fn measure(name:&str, times:usize, step:impl Fn(&mut Output, usize)){
+        let mut bytes = [0; 2];
+        for (full, total) in [true, false].into_iter().zip(bytes.iter_mut()){
+            let mut output = sample(full);
+            let started = Instant::now();
+            for i in 0..times{
+                step(&mut output, i);
+                *total += render(&mut output, full);
+            }
+            println!("{:<25} {:>6} bytes in {:?}{}", name, total, started.elapsed(), if full {" (full repaint)"} else {""});
+        }
+        assert!(bytes[1] < bytes[0]);
+    }
+
+    /// Bytes sent to an 80x24 terminal on a 200-line file, redrawing only
+    /// changed lines against repainting all of them. Run with
+    /// `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn redraw_bytes(){
+        measure("40 typed characters", 40, |output, _| output.insert_char('x'));
+        measure("60 cursor-down scrolls", 60, |output, _| output.move_cursor(KeyCode::Down));
+        measure("20 characters in prompt", 20, |output, i| {
+            output.status_message.set_message(format!("Search: {}", "x".repeat(i + 1)));
+        });
+    }
+}
@@ -0,0 +1,109 @@
+//! Line-based merge of the buffer with a copy of the file changed on disk.
+
+use std::cmp;
+
+/// Largest number of line pairs compared when aligning the changed middle
+/// of two versions; anything bigger becomes a single conflict.
+const ALIGN_LIMIT: usize = 4_000_000;
+
+/// Keeps the lines both versions share and wraps each run of differing
+/// lines in conflict markers, `mine` first, as `git merge` does.
+pub fn conflict_merge(mine:&[String], theirs:&[String]) -> Vec<String>{
+    let prefix = mine.iter().zip(theirs).take_while(|(a, b)| a == b).count();
+    let suffix = mine[prefix..]
+        .iter()
+        .rev()
+        .zip(theirs[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &mine[prefix..mine.len() - suffix];
+    let b = &theirs[prefix..theirs.len() - suffix];
+
+    let mut merged = mine[..prefix].to_vec();
+    let (mut ours, mut other) = (Vec::new(), Vec::new());
+    if a.len() * b.len() <= ALIGN_LIMIT{
+        // common[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+        let mut common = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev(){
+            for j in (0..b.len()).rev(){
+                common[i][j] = if a[i] == b[j]{
+                    common[i + 1][j + 1] + 1
+                }else{
+                    cmp::max(common[i + 1][j], common[i][j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len(){
+            if i < a.len() && j < b.len() && a[i] == b[j]{
+                push_conflict(&mut merged, &mut ours, &mut other);
+                merged.push(a[i].clone());
+                i += 1;
+                j += 1;
+            }else if j == b.len() || (i < a.len() && common[i + 1][j] >= common[i][j + 1]){
+                ours.push(a[i].clone());
+                i += 1;
+            }else{
+                other.push(b[j].clone());
+                j += 1;
+            }
+        }
+    }else{
+        ours.extend_from_slice(a);
+        other.extend_from_slice(b);
+    }
+    push_conflict(&mut merged, &mut ours, &mut other);
+    merged.extend_from_slice(&mine[mine.len() - suffix..]);
+    merged
+}
+
+fn push_conflict(merged:&mut Vec<String>, ours:&mut Vec<String>, other:&mut Vec<String>){
+    if ours.is_empty() && other.is_empty(){
+        return;
+    }
+    merged.push("<<<<<<< buffer".into());
+    merged.append(ours);
+    merged.push("=======".into());
+    merged.append(other);
+    merged.push(">>>>>>> disk".into());
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn lines(text:&str) -> Vec<String>{
+        text.lines().map(String::from).collect()
+    }
+
+    fn merge(mine:&str, theirs:&str) -> String{
+        conflict_merge(&lines(mine), &lines(theirs)).join("\n")
+    }
+
+    #[test]
+    fn keeps_identical_versions_as_they_are(){
+        assert_eq!(merge("a\nb\nc", "a\nb\nc"), "a\nb\nc");
+        assert_eq!(merge("", ""), "");
+    }
+
+    #[test]
+    fn marks_lines_only_one_side_has(){
+        assert_eq!(
+            merge("a\nb\nc", "a\nc"),
+            "a\n<<<<<<< buffer\nb\n=======\n>>>>>>> disk\nc"
+        );
+        assert_eq!(
+            merge("a\nc", "a\nb\nc\nd"),
+            "a\n<<<<<<< buffer\n=======\nb\n>>>>>>> disk\nc\n<<<<<<< buffer\n=======\nd\n>>>>>>> disk"
+        );
+    }
+
+    #[test]
+    fn wraps_overlapping_edits_in_one_conflict(){
+        assert_eq!(
+            merge("head\nmine 1\nmine 2\nsame\ntail", "head\ntheirs 1\nsame\ntail"),
+            "head\n<<<<<<< buffer\nmine 1\nmine 2\n=======\ntheirs 1\n>>>>>>> disk\nsame\ntail"
+        );
+    }
+}
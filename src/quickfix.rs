@@ -0,0 +1,116 @@
+//! Locations picked out of compiler output, for stepping through errors.
+
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Clone)]
+pub struct Entry{
+    pub file:PathBuf,
+    /// 1-based, as compilers print them.
+    pub line:usize,
+    pub col:Option<usize>,
+    pub message:String
+}
+
+impl fmt::Display for Entry{
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result{
+        write!(f, "{}:{}", self.file.display(), self.line)?;
+        if let Some(col) = self.col{
+            write!(f, ":{}", col)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Collects the errors and warnings in build output. Understands rustc,
+/// which puts the location on a `-->` line under the message, and the
+/// `file:line[:col]: message` form of gcc, clang and most other tools.
+pub fn parse(output:&str) -> Vec<Entry>{
+    let mut entries = Vec::new();
+    let mut heading = None;
+    for line in output.lines(){
+        if let Some(location) = line.trim_start().strip_prefix("--> "){
+            // Only the first location belongs to the message; later ones are notes.
+            if let (Some(message), Some(mut entry)) = (heading.take(), parse_location(location)){
+                entry.message = message;
+                entries.push(entry);
+            }
+        }else if let Some(entry) = parse_location(line){
+            entries.push(entry);
+        }else if line.starts_with("error") || line.starts_with("warning"){
+            heading = Some(line.trim().to_string());
+        }
+    }
+    entries
+}
+
+/// Parses `file:line[:col][: message]`.
+fn parse_location(text:&str) -> Option<Entry>{
+    let mut parts = text.trim_end().splitn(4, ':');
+    let file = parts.next()?;
+    // Rules out prose such as "In file included from x.h:3:".
+    if file.is_empty() || file.contains(char::is_whitespace){
+        return None;
+    }
+    let line = parts.next()?.parse::<usize>().ok().filter(|line| *line > 0)?;
+    let rest:Vec<&str> = parts.collect();
+    let (col, message) = match rest.first().and_then(|col| col.parse::<usize>().ok()){
+        Some(col) => (Some(col), rest[1..].join(":")),
+        None => (None, rest.join(":")),
+    };
+    Some(Entry{file:file.into(), line, col, message:message.trim().to_string()})
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn parsed(output:&str) -> Vec<String>{
+        parse(output).iter().map(Entry::to_string).collect()
+    }
+
+    #[test]
+    fn reads_rustc_locations_under_their_message(){
+        let output = "   Compiling editor v0.1.0 (/src/editor)
+error[E0425]: cannot find value `x` in this scope
+ --> src/main.rs:3:5
+  |
+3 |     x
+  |     ^ not found in this scope
+  |
+note: defined here
+ --> src/lib.rs:1:1
+warning: unused variable: `y`
+  --> src/lib.rs:10:9
+";
+        assert_eq!(parsed(output), [
+            "src/main.rs:3:5: error[E0425]: cannot find value `x` in this scope",
+            "src/lib.rs:10:9: warning: unused variable: `y`",
+        ]);
+    }
+
+    #[test]
+    fn reads_file_line_column_messages(){
+        let output = "main.c:12:5: error: expected ';' before '}' token
+lib/util.h:7: warning: unused parameter: 'n'
+src/a.rs:20:fn main() {
+";
+        assert_eq!(parsed(output), [
+            "main.c:12:5: error: expected ';' before '}' token",
+            "lib/util.h:7: warning: unused parameter: 'n'",
+            "src/a.rs:20: fn main() {",
+        ]);
+    }
+
+    #[test]
+    fn skips_lines_that_are_not_locations(){
+        let output = "In file included from x.h:3:
+make: *** [Makefile:4: all] Error 1
+main.c:0: line zero
+main.c:x: no line
+:3: no file
+build finished with 2 errors
+";
+        assert!(parsed(output).is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+//! Writing files so that a crash or a full disk never leaves one half
+//! written: the new contents go to a temporary file next to the original,
+//! which is then renamed over it.
+
+use std::collections::hash_map::RandomState;
+use std::ffi::OsString;
+use std::fs;
+use std::hash::BuildHasher;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Writes `target` with `write`, through a symlink to the file it points
+/// at. The original's permissions and, where allowed, owner and group
+/// carry over to the new file. A file with other hard links, or in a
+/// directory the editor cannot create files in, is written in place
+/// instead, as renaming would detach it from its other names.
+pub fn write_file<T>(target:&Path, write:impl FnOnce(&fs::File) -> io::Result<T>) -> io::Result<T>{
+    let target = fs::canonicalize(target).unwrap_or_else(|_| target.to_path_buf());
+    let metadata = fs::metadata(&target).ok();
+    if metadata.as_ref().is_some_and(hard_linked){
+        return write_in_place(&target, write);
+    }
+    let (file, temp) = match create_temp(&target, metadata.as_ref()){
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return write_in_place(&target, write),
+        result => result?,
+    };
+
+    let result = write(&file).and_then(|written| {
+        file.sync_all()?;
+        if let Some(metadata) = &metadata{
+            copy_owner(&file, metadata);
+            file.set_permissions(metadata.permissions())?;
+        }
+        fs::rename(&temp, &target)?;
+        Ok(written)
+    });
+    if result.is_err(){
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_in_place<T>(target:&Path, write:impl FnOnce(&fs::File) -> io::Result<T>) -> io::Result<T>{
+    let file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(target)?;
+    let written = write(&file)?;
+    file.sync_all()?;
+    Ok(written)
+}
+
+/// Creates `.name.<pid>.<random>.tmp` next to `target`, with a new random
+/// part for as long as the name is taken. It starts out with the access
+/// bits of the `original`, so that a private file is never readable by
+/// others while the new contents are written.
+fn create_temp(target:&Path, original:Option<&fs::Metadata>) -> io::Result<(fs::File, PathBuf)>{
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    if let Some(metadata) = original{
+        copy_mode(&mut options, metadata);
+    }
+
+    let state = RandomState::new();
+    for attempt in 0u32..{
+        let mut name = OsString::from(".");
+        name.push(target.file_name().unwrap_or_default());
+        name.push(format!(".{}.{:08x}.tmp", process::id(), state.hash_one(attempt) as u32));
+        let temp = target.with_file_name(name);
+        match options.open(&temp){
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            result => return result.map(|file| (file, temp)),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(unix)]
+fn hard_linked(metadata:&fs::Metadata) -> bool{
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn hard_linked(_metadata:&fs::Metadata) -> bool{
+    false
+}
+
+#[cfg(unix)]
+fn copy_mode(options:&mut fs::OpenOptions, metadata:&fs::Metadata){
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    options.mode(metadata.permissions().mode() & 0o777);
+}
+
+#[cfg(not(unix))]
+fn copy_mode(_options:&mut fs::OpenOptions, _metadata:&fs::Metadata){}
+
+/// Gives `file` the owner and group of the original. Only root can give a
+/// file away, but the group can still be one the user belongs to.
+#[cfg(unix)]
+fn copy_owner(file:&fs::File, metadata:&fs::Metadata){
+    use std::os::unix::fs::{fchown, MetadataExt};
+
+    if fchown(file, Some(metadata.uid()), Some(metadata.gid())).is_err(){
+        let _ = fchown(file, None, Some(metadata.gid()));
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_file:&fs::File, _metadata:&fs::Metadata){}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn never_widens_access_to_a_private_file(){
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("editor-save-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join(".env");
+        fs::write(&file, "old").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        let mode = |metadata:fs::Metadata| metadata.permissions().mode() & 0o777;
+        let while_writing = write_file(&file, |mut out| {
+            io::Write::write_all(&mut out, b"new")?;
+            out.metadata().map(mode)
+        });
+        let after = fs::metadata(&file).map(mode);
+        let contents = fs::read_to_string(&file);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(while_writing.unwrap(), 0o600);
+        assert_eq!(after.unwrap(), 0o600);
+        assert_eq!(contents.unwrap(), "new");
+    }
+}
@@ -0,0 +1,93 @@
+//! Shell commands run from the editor, with their output captured.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+pub struct Finished{
+    pub stdout:String,
+    pub stderr:String,
+    /// `None` when the command was killed by a signal.
+    pub code:Option<i32>
+}
+
+impl Finished{
+    pub fn success(&self) -> bool{
+        self.code == Some(0)
+    }
+
+    /// How the command ended, followed by the first line it wrote to
+    /// stderr, for the message bar.
+    pub fn describe(&self) -> String{
+        let status = match self.code{
+            Some(0) => "finished".to_string(),
+            Some(code) => format!("exited with status {}", code),
+            None => "was killed by a signal".to_string(),
+        };
+        match self.first_error(){
+            Some(line) => format!("{}: {}", status, line),
+            None => status,
+        }
+    }
+
+    /// The first line of stderr that is not blank.
+    pub fn first_error(&self) -> Option<&str>{
+        self.stderr.lines().map(str::trim).find(|line| !line.is_empty())
+    }
+}
+
+/// Runs `command` through the system shell and waits for it. With `input`
+/// it is fed that on stdin, otherwise stdin is empty.
+pub fn run(command:&str, input:Option<String>) -> io::Result<Finished>{
+    let mut child = shell_command(command)
+        .stdin(if input.is_some() {Stdio::piped()} else {Stdio::null()})
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Fed from another thread, as a command may fill its output pipe
+    // before it has read all of its input.
+    let feeder = input.map(|input| {
+        let mut stdin = child.stdin.take().unwrap();
+        thread::spawn(move || stdin.write_all(input.as_bytes()))
+    });
+    let output = child.wait_with_output()?;
+    if let Some(feeder) = feeder{
+        match feeder.join().unwrap(){
+            // Commands like `head` are free to stop reading early.
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+            _ => {},
+        }
+    }
+
+    Ok(Finished{
+        stdout:String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr:String::from_utf8_lossy(&output.stderr).into_owned(),
+        code:output.status.code()
+    })
+}
+
+/// `arg` quoted to reach a command run by the system shell as one argument.
+#[cfg(unix)]
+pub fn quote(arg:&str) -> String{
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+#[cfg(not(unix))]
+pub fn quote(arg:&str) -> String{
+    format!("\"{}\"", arg)
+}
+
+/// `command` as run by the system shell.
+#[cfg(unix)]
+pub fn shell_command(command:&str) -> Command{
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+pub fn shell_command(command:&str) -> Command{
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
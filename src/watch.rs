@@ -0,0 +1,119 @@
+//! Notices when other programs change a file. On Linux the kernel reports
+//! it through inotify; elsewhere, or when that cannot be set up, every check
+//! says the file may have changed and the caller looks at it itself.
+
+use std::path::Path;
+
+pub struct Watch{
+    /// `None` when falling back to polling.
+    inotify:Option<Inotify>
+}
+
+impl Watch{
+    pub fn new(file:&Path) -> Self{
+        Self{inotify:Inotify::new(file)}
+    }
+
+    /// Whether the file may have changed since the last call.
+    pub fn changed(&mut self) -> bool{
+        match &self.inotify{
+            Some(inotify) => match inotify.changed(){
+                Some(changed) => changed,
+                None => {
+                    // The directory went away or events were lost.
+                    self.inotify = None;
+                    true
+                },
+            },
+            None => true,
+        }
+    }
+}
+
+/// An inotify instance watching the directory the file is in. Editors and
+/// the editor itself replace files by renaming a new one over them, which a
+/// watch on the file alone would stop following.
+#[cfg(target_os = "linux")]
+struct Inotify{
+    fd:libc::c_int,
+    name:Vec<u8>
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify{
+    fn new(file:&Path) -> Option<Self>{
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let name = file.file_name()?.as_bytes().to_vec();
+        let dir = match file.parent(){
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+        let mask = libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_CLOSE_WRITE | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF;
+        unsafe{
+            let fd = libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC);
+            if fd < 0{
+                return None;
+            }
+            if libc::inotify_add_watch(fd, dir.as_ptr(), mask) < 0{
+                libc::close(fd);
+                return None;
+            }
+            Some(Self{fd, name})
+        }
+    }
+
+    /// Reads the events queued so far: whether any was about the file, or
+    /// `None` if the watch no longer tells the whole story.
+    fn changed(&self) -> Option<bool>{
+        const HEADER:usize = 16;
+        let mut buffer = [0u8; 4096];
+        let mut changed = false;
+        loop{
+            let read = unsafe{libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len())};
+            if read <= 0{
+                return Some(changed);
+            }
+            let events = &buffer[..read as usize];
+            let mut at = 0;
+            while at + HEADER <= events.len(){
+                let field = |offset:usize| u32::from_ne_bytes(events[at + offset..at + offset + 4].try_into().unwrap());
+                let (mask, len) = (field(4), field(12) as usize);
+                if mask & (libc::IN_Q_OVERFLOW | libc::IN_IGNORED | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0{
+                    return None;
+                }
+                let end = (at + HEADER + len).min(events.len());
+                let name = &events[at + HEADER..end];
+                let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(name.len())];
+                changed |= name == self.name.as_slice();
+                at = end;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Inotify{
+    fn drop(&mut self){
+        unsafe{
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Inotify;
+
+#[cfg(not(target_os = "linux"))]
+impl Inotify{
+    fn new(_file:&Path) -> Option<Self>{
+        None
+    }
+
+    fn changed(&self) -> Option<bool>{
+        Some(true)
+    }
+}